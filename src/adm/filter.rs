@@ -3,12 +3,16 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
     iter::FromIterator,
-    sync::{Arc, RwLock},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use actix_http::http::Uri;
 use actix_web_location::Location;
+use cadence::StatsdClient;
 use lazy_static::lazy_static;
 use url::Url;
 
@@ -20,6 +24,8 @@ use crate::{
     adm::settings::PathMatching,
     error::{HandlerError, HandlerErrorKind, HandlerResult},
     metrics::Metrics,
+    server::cache::TilesCache,
+    settings::{PositionPrecedence, Settings},
     tags::Tags,
     web::middleware::sentry as l_sentry,
     web::DeviceInfo,
@@ -56,6 +62,8 @@ pub struct AdmFilter {
     /// Temporary list of advertisers with legacy images built into firefox
     /// for pre 91 tile support.
     pub legacy_list: HashSet<String>,
+    /// Whether the advertiser settings' or the partner's tile positions win
+    pub position_precedence: PositionPrecedence,
     pub source: String,
     pub source_url: Option<url::Url>,
     pub last_updated: Option<chrono::DateTime<chrono::Utc>>,
@@ -104,19 +112,49 @@ fn check_url(url: Url, species: &'static str, filter: &[Vec<String>]) -> Handler
     Err(HandlerErrorKind::UnexpectedHost(species, host).into())
 }
 
-pub fn spawn_updater(filter: &Arc<RwLock<AdmFilter>>, req: reqwest::Client) {
+/// Evict the cached tiles of the advertisers whose filter settings changed
+/// (here and from the shared backend), so they aren't served until the
+/// entries expire
+async fn invalidate_tiles(tiles_cache: &TilesCache, changed: &HashSet<String>, metrics: &Metrics) {
+    let invalidated = tiles_cache.invalidate_advertisers(changed);
+    tiles_cache.shared_delete(&invalidated, metrics).await;
+    let count = invalidated.len();
+    if count > 0 {
+        info!(
+            "Invalidated {} tiles cache entries for updated advertisers {:?}",
+            count, changed
+        );
+    }
+    metrics.count("tiles_cache.invalidated", count as i64);
+}
+
+pub fn spawn_updater(
+    filter: &Arc<RwLock<AdmFilter>>,
+    req: reqwest::Client,
+    tiles_cache: &TilesCache,
+    metrics: &StatsdClient,
+) {
     if !filter.read().unwrap().is_cloud() {
         return;
     }
     let mfilter = filter.clone();
+    let tiles_cache = tiles_cache.clone();
+    let metrics = Metrics::from(metrics);
     actix_rt::spawn(async move {
         let tags = crate::tags::Tags::default();
         loop {
-            let mut filter = mfilter.write().unwrap();
+            // Work on a copy so the filter isn't locked while talking to the
+            // bucket (or sleeping)
+            let filter = mfilter.read().unwrap().clone();
             match filter.requires_update(&req).await {
-                Ok(true) => filter.update().await.unwrap_or_else(|e| {
-                    filter.report(&e, &tags);
-                }),
+                Ok(true) => match filter.fetch_settings().await {
+                    Ok(Some(adm_settings)) => {
+                        let changed = mfilter.write().unwrap().apply(adm_settings);
+                        invalidate_tiles(&tiles_cache, &changed, &metrics).await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => filter.report(&e, &tags),
+                },
                 Ok(false) => {}
                 Err(e) => {
                     filter.report(&e, &tags);
@@ -127,6 +165,107 @@ pub fn spawn_updater(filter: &Arc<RwLock<AdmFilter>>, req: reqwest::Client) {
     });
 }
 
+/// On demand checks for updated filter settings, triggered by tile requests
+/// (`adm_live_update`).
+///
+/// Complements [spawn_updater]'s polling so that urgent changes (e.g. an
+/// advertiser removal) take effect within seconds. Checks are rate limited to
+/// one per `adm_live_update_interval_secs` and single flight: at most one
+/// runs at a time, in the background, never delaying the tile request.
+#[derive(Clone, Debug)]
+pub struct LiveUpdater {
+    filter: Arc<RwLock<AdmFilter>>,
+    req: reqwest::Client,
+    tiles_cache: TilesCache,
+    interval: Duration,
+    last_check: Arc<Mutex<Option<Instant>>>,
+    checking: Arc<AtomicBool>,
+}
+
+impl LiveUpdater {
+    /// Create a LiveUpdater if enabled (and the settings are in a bucket)
+    pub fn from_settings(
+        settings: &Settings,
+        filter: &Arc<RwLock<AdmFilter>>,
+        req: &reqwest::Client,
+        tiles_cache: &TilesCache,
+    ) -> Option<Self> {
+        if !settings.adm_live_update || !filter.read().unwrap().is_cloud() {
+            return None;
+        }
+        Some(Self {
+            filter: filter.clone(),
+            req: req.clone(),
+            tiles_cache: tiles_cache.clone(),
+            interval: Duration::from_secs(settings.adm_live_update_interval_secs),
+            last_check: Default::default(),
+            checking: Default::default(),
+        })
+    }
+
+    /// Spawn a check for updated settings, unless one was recently made or
+    /// is already in flight
+    pub fn check(&self, metrics: &Metrics) {
+        let checking = match self.begin_check() {
+            Some(checking) => checking,
+            None => return,
+        };
+        metrics.incr("filter.adm.live_update.check");
+
+        let updater = self.clone();
+        let metrics = metrics.clone();
+        actix_rt::spawn(async move {
+            // Held until the check completes (or panics)
+            let _checking = checking;
+            let tags = Tags::default();
+            // Work on a copy so the filter isn't locked while talking to
+            // the bucket
+            let filter = updater.filter.read().unwrap().clone();
+            match filter.requires_update(&updater.req).await {
+                Ok(true) => match filter.fetch_settings().await {
+                    Ok(Some(adm_settings)) => updater.apply(adm_settings, &metrics).await,
+                    Ok(None) => {}
+                    Err(e) => filter.report(&e, &tags),
+                },
+                Ok(false) => {}
+                Err(e) => filter.report(&e, &tags),
+            }
+        });
+    }
+
+    /// Start a check, unless one was recently made or is already in flight.
+    ///
+    /// The check is in flight until the returned guard is dropped.
+    fn begin_check(
+        &self,
+    ) -> Option<scopeguard::ScopeGuard<Arc<AtomicBool>, impl FnOnce(Arc<AtomicBool>)>> {
+        let mut last_check = self.last_check.lock().unwrap();
+        if last_check.map_or(false, |last| last.elapsed() < self.interval) {
+            return None;
+        }
+        if self
+            .checking
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return None;
+        }
+        *last_check = Some(Instant::now());
+        Some(scopeguard::guard(self.checking.clone(), |checking| {
+            checking.store(false, Ordering::Release)
+        }))
+    }
+
+    /// Apply updated settings to the shared filter (merging them in, so an
+    /// update made meanwhile by [spawn_updater] isn't lost)
+    async fn apply(&self, adm_settings: AdmFilterSettings, metrics: &Metrics) {
+        trace!("LiveUpdater: applying updated settings");
+        let changed = self.filter.write().unwrap().apply(adm_settings);
+        metrics.incr("filter.adm.live_update.updated");
+        invalidate_tiles(&self.tiles_cache, &changed, metrics).await;
+    }
+}
+
 /// Filter a given tile data set provided by ADM and validate the various elements
 impl AdmFilter {
     /// convenience function to determine if settings are cloud ready.
@@ -137,6 +276,17 @@ impl AdmFilter {
         false
     }
 
+    /// Whether any advertiser (or DEFAULT) ignores the DMA (`ignore_dmas`)
+    pub fn ignores_dma(&self, dma: u16) -> bool {
+        self.filter_set.values().any(|filter| {
+            filter
+                .ignore_dmas
+                .iter()
+                .flatten()
+                .any(|ignored| *ignored == dma)
+        })
+    }
+
     /// Report the error directly to sentry
     fn report(&self, error: &HandlerError, tags: &Tags) {
         // trace!(&error, &tags);
@@ -172,38 +322,71 @@ impl AdmFilter {
     }
 
     /// Try to update the ADM filter data from the remote bucket.
-    pub async fn update(&mut self) -> HandlerResult<()> {
-        if let Some(bucket) = &self.source_url {
-            let adm_settings = AdmFilterSettings::from_settings_bucket(
-                bucket,
-                self.connect_timeout,
-                self.request_timeout,
-            )
-            .await
-            .map_err(|e| {
-                HandlerError::internal(&format!(
-                    "Invalid bucket data in {:?}: {:?}",
-                    self.source, e
-                ))
-            })?;
-            for (adv, setting) in adm_settings.advertisers {
-                if setting.delete {
-                    trace!("Removing advertiser {:?}", &adv);
-                    self.filter_set.remove(&adv.to_lowercase());
-                };
-                trace!("Processing records for {:?}", &adv);
-                // DEFAULT included but sans special processing -- close enough
-                for country in &setting.include_regions {
-                    if !self.all_include_regions.contains(country) {
-                        self.all_include_regions.insert(country.clone());
-                    }
+    ///
+    /// Returns the (lowercased) names of the advertisers whose settings
+    /// changed.
+    pub async fn update(&mut self) -> HandlerResult<HashSet<String>> {
+        Ok(match self.fetch_settings().await? {
+            Some(adm_settings) => self.apply(adm_settings),
+            None => HashSet::new(),
+        })
+    }
+
+    /// Read the ADM filter settings from the remote bucket (if any)
+    pub async fn fetch_settings(&self) -> HandlerResult<Option<AdmFilterSettings>> {
+        let bucket = match &self.source_url {
+            Some(bucket) => bucket,
+            None => return Ok(None),
+        };
+        let adm_settings = AdmFilterSettings::from_settings_bucket(
+            bucket,
+            self.connect_timeout,
+            self.request_timeout,
+        )
+        .await
+        .map_err(|e| {
+            HandlerError::internal(&format!(
+                "Invalid bucket data in {:?}: {:?}",
+                self.source, e
+            ))
+        })?;
+        Ok(Some(adm_settings))
+    }
+
+    /// Merge updated filter settings, returning the (lowercased) names of
+    /// the advertisers whose settings changed (all of them when the
+    /// `DEFAULT` settings did).
+    pub(crate) fn apply(&mut self, adm_settings: AdmFilterSettings) -> HashSet<String> {
+        let mut changed = HashSet::new();
+        for (adv, setting) in adm_settings.advertisers {
+            let name = adv.to_lowercase();
+            if setting.delete {
+                trace!("Removing advertiser {:?}", &adv);
+                // Repeated deletes (e.g. of each update) change nothing
+                if self.filter_set.remove(&name).is_some() {
+                    changed.insert(name);
+                }
+                continue;
+            }
+            if self.filter_set.get(&name) != Some(&setting) {
+                changed.insert(name.clone());
+            }
+            trace!("Processing records for {:?}", &adv);
+            // DEFAULT included but sans special processing -- close enough
+            for country in &setting.include_regions {
+                if !self.all_include_regions.contains(country) {
+                    self.all_include_regions.insert(country.clone());
                 }
-                // map the settings to the URL we're going to be checking
-                self.filter_set.insert(adv.to_lowercase(), setting);
             }
-            self.last_updated = Some(chrono::Utc::now());
+            // map the settings to the URL we're going to be checking
+            self.filter_set.insert(name, setting);
         }
-        Ok(())
+        // Advertisers without their own values fall back to DEFAULT's
+        if changed.contains(&DEFAULT.to_lowercase()) {
+            changed.extend(self.filter_set.keys().cloned());
+        }
+        self.last_updated = Some(chrono::Utc::now());
+        changed
     }
 
     /// Check the advertiser URL
@@ -407,6 +590,16 @@ impl AdmFilter {
                     metrics.incr_with_tags("filter.adm.err.invalid_location", Some(tags));
                     return None;
                 }
+                let ignore_dmas = filter
+                    .ignore_dmas
+                    .as_ref()
+                    .filter(|dmas| !dmas.is_empty())
+                    .or(default.ignore_dmas.as_ref());
+                if ignore_dmas.map_or(false, |dmas| dmas.contains(&location.dma())) {
+                    trace!("Rejecting tile: DMA {:?} ignored", location.dma());
+                    metrics.incr_with_tags("filter.adm.err.ignored_dma", Some(tags));
+                    return None;
+                }
                 // match to the version that we switched over from built in image management
                 // to CDN image fetch. Note: iOS does not use the standard firefox version number
 
@@ -475,6 +668,11 @@ impl AdmFilter {
                 // Use the default.position (Option<u8>) if the filter.position (Option<u8>) isn't
                 // defined. In either case `None` is a valid return, but we should favor `filter` over
                 // `default`.
+                let settings_position = filter.position.or(default.position);
+                tile.position = match self.position_precedence {
+                    PositionPrecedence::Settings => settings_position.or(tile.position),
+                    PositionPrecedence::Partner => tile.position.or(settings_position),
+                };
                 Some(Tile::from_adm_tile(tile))
             }
             None => {
@@ -493,11 +691,143 @@ impl AdmFilter {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::sync::{atomic::Ordering, Arc, RwLock};
+    use std::time::Duration;
+
     use crate::adm::tiles::AdmTile;
-    use crate::adm::AdmAdvertiserFilterSettings;
+    use crate::adm::{AdmAdvertiserFilterSettings, AdmFilterSettings};
+    use crate::metrics::Metrics;
+    use crate::server::cache::TilesCache;
+    use crate::settings::Settings;
     use crate::tags::Tags;
 
-    use super::{check_url, AdmFilter};
+    use super::{check_url, AdmFilter, LiveUpdater};
+
+    fn live_updater(filter: &Arc<RwLock<AdmFilter>>) -> LiveUpdater {
+        let settings = Settings {
+            adm_live_update: true,
+            ..Default::default()
+        };
+        LiveUpdater::from_settings(
+            &settings,
+            filter,
+            &reqwest::Client::new(),
+            &TilesCache::new(10),
+        )
+        .expect("No LiveUpdater")
+    }
+
+    fn cloud_filter() -> Arc<RwLock<AdmFilter>> {
+        Arc::new(RwLock::new(AdmFilter {
+            source_url: Some("gs://bucket/settings.json".parse().unwrap()),
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn live_update_rate_limited() {
+        let mut updater = live_updater(&cloud_filter());
+        let checking = updater.begin_check();
+        assert!(checking.is_some());
+        // Single flight
+        updater.interval = Duration::from_secs(0);
+        assert!(updater.begin_check().is_none());
+        drop(checking);
+        assert!(updater.begin_check().is_some());
+
+        // One check per interval
+        updater.interval = Duration::from_secs(60);
+        assert!(updater.begin_check().is_none());
+    }
+
+    #[test]
+    fn live_update_panicked() {
+        let updater = live_updater(&cloud_filter());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _checking = updater.begin_check().expect("No check");
+            panic!("Check failed");
+        }));
+        assert!(result.is_err());
+        // The failed check doesn't disable later ones
+        assert!(!updater.checking.load(Ordering::Acquire));
+    }
+
+    #[actix_rt::test]
+    async fn live_update_apply() {
+        let filter = cloud_filter();
+        let updater = live_updater(&filter);
+        // Updated by spawn_updater meanwhile
+        filter
+            .write()
+            .unwrap()
+            .filter_set
+            .insert("acme".to_owned(), Default::default());
+
+        let adm_settings = AdmFilterSettings {
+            advertisers: vec![("Dunder Mifflin".to_owned(), Default::default())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        updater.apply(adm_settings, &Metrics::noop()).await;
+        let filter = filter.read().unwrap();
+        assert!(filter.filter_set.contains_key("acme"));
+        assert!(filter.filter_set.contains_key("dunder mifflin"));
+        assert!(filter.last_updated.is_some());
+    }
+
+    #[test]
+    fn apply_changed() {
+        let setting = |include_regions: &[&str], delete| AdmAdvertiserFilterSettings {
+            include_regions: include_regions.iter().map(|r| (*r).to_owned()).collect(),
+            delete,
+            ..Default::default()
+        };
+        let update = |advertisers: Vec<(&str, AdmAdvertiserFilterSettings)>| AdmFilterSettings {
+            advertisers: advertisers
+                .into_iter()
+                .map(|(adv, setting)| (adv.to_owned(), setting))
+                .collect(),
+            ..Default::default()
+        };
+        let names = |names: &[&str]| -> HashSet<String> {
+            names.iter().map(|name| (*name).to_owned()).collect()
+        };
+        let mut filter = AdmFilter::default();
+
+        let changed = filter.apply(update(vec![
+            ("Acme", setting(&["US", "CA"], false)),
+            ("Dunder Mifflin", setting(&["US"], false)),
+        ]));
+        assert_eq!(changed, names(&["acme", "dunder mifflin"]));
+
+        // Unchanged settings don't invalidate anything
+        let changed = filter.apply(update(vec![
+            ("Acme", setting(&["US", "CA"], false)),
+            ("Dunder Mifflin", setting(&["US"], false)),
+        ]));
+        assert!(changed.is_empty());
+
+        // Tightened regions and removals do
+        let changed = filter.apply(update(vec![
+            ("Acme", setting(&["US"], false)),
+            ("Dunder Mifflin", setting(&["US"], true)),
+        ]));
+        assert_eq!(changed, names(&["acme", "dunder mifflin"]));
+        assert!(!filter.filter_set.contains_key("dunder mifflin"));
+
+        // Deleting them again doesn't
+        let changed = filter.apply(update(vec![
+            ("Acme", setting(&["US"], false)),
+            ("Dunder Mifflin", setting(&["US"], true)),
+        ]));
+        assert!(changed.is_empty());
+
+        // As does DEFAULT, for everyone
+        let changed = filter.apply(update(vec![("DEFAULT", setting(&["GB"], false))]));
+        assert_eq!(changed, names(&["acme", "default"]));
+    }
 
     #[test]
     fn check_url_matches() {
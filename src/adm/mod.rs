@@ -7,9 +7,15 @@
 //! offered matches expected values.
 
 mod filter;
+mod provider;
+mod query;
+mod recording;
 mod settings;
 mod tiles;
 
-pub use filter::{spawn_updater, AdmFilter};
+pub use filter::{spawn_updater, AdmFilter, LiveUpdater};
+pub use provider::AdmProvider;
+pub use query::{QueryAttribute, QueryParam, QueryTemplate};
+pub use settings::PseRule;
 pub(crate) use settings::{AdmAdvertiserFilterSettings, AdmFilterSettings, AdmPse, DEFAULT};
-pub use tiles::{get_tiles, TileResponse};
+pub use tiles::{get_tiles, Tile, TileResponse};
@@ -0,0 +1,69 @@
+//! adM as a [TileProvider]
+use actix_http::http::header::HeaderMap;
+use actix_web_location::Location;
+use async_trait::async_trait;
+
+use super::{get_tiles, TileResponse};
+use crate::{
+    error::HandlerResult,
+    metrics::Metrics,
+    providers::{CircuitBreaker, PartnerSettings, TileProvider},
+    server::ServerState,
+    settings::Settings,
+    tags::Tags,
+    web::DeviceInfo,
+};
+
+/// Fetches tiles from an adM Tiles API partner endpoint
+#[derive(Debug)]
+pub struct AdmProvider {
+    partner: PartnerSettings,
+    breaker: CircuitBreaker,
+}
+
+impl AdmProvider {
+    pub fn new(partner: PartnerSettings, settings: &Settings) -> Self {
+        let breaker = CircuitBreaker::new(&partner.name, settings);
+        Self { partner, breaker }
+    }
+}
+
+#[async_trait(?Send)]
+impl TileProvider for AdmProvider {
+    fn name(&self) -> &str {
+        &self.partner.name
+    }
+
+    fn priority(&self) -> u8 {
+        self.partner.priority
+    }
+
+    fn max_tiles(&self) -> Option<u8> {
+        self.partner.max_tiles
+    }
+
+    fn breaker(&self) -> Option<&CircuitBreaker> {
+        Some(&self.breaker)
+    }
+
+    async fn get_tiles(
+        &self,
+        state: &ServerState,
+        location: &Location,
+        device_info: &DeviceInfo,
+        tags: &mut Tags,
+        metrics: &Metrics,
+        headers: Option<&HeaderMap>,
+    ) -> HandlerResult<TileResponse> {
+        get_tiles(
+            state,
+            &self.partner,
+            location,
+            device_info,
+            tags,
+            metrics,
+            headers,
+        )
+        .await
+    }
+}
@@ -0,0 +1,181 @@
+//! Templates for the query strings sent to the adM Tiles API
+use std::convert::TryFrom;
+
+use config::ConfigError;
+use serde::{Deserialize, Serialize};
+
+/// An audience (or partner) attribute that may be sent to the partner
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryAttribute {
+    PartnerId,
+    Sub1,
+    /// The country (or `fallback_country` when unknown)
+    CountryCode,
+    RegionCode,
+    /// The DMA code (empty when excluded via `exclude_dma`)
+    DmaCode,
+    FormFactor,
+    OsFamily,
+    /// The number of tiles requested (`adm_query_tile_count`)
+    Results,
+}
+
+/// A single query parameter, sending either an attribute or a static value
+/// under the given name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryParam {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribute: Option<QueryAttribute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl QueryParam {
+    fn attribute(name: &str, attribute: QueryAttribute) -> Self {
+        Self {
+            name: name.to_owned(),
+            attribute: Some(attribute),
+            value: None,
+        }
+    }
+
+    fn value(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            attribute: None,
+            value: Some(value.to_owned()),
+        }
+    }
+}
+
+/// The query parameters sent to a partner endpoint, in order.
+///
+/// Specified as a JSON list, e.g.
+///
+/// ```json
+/// [
+///     {"name": "partner", "attribute": "partner_id"},
+///     {"name": "country-code", "attribute": "country_code"},
+///     {"name": "v", "value": "1.0"}
+/// ]
+/// ```
+///
+/// Each parameter has a `name` and either an `attribute` (one of
+/// [QueryAttribute]) or a static `value`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "Vec<QueryParam>")]
+pub struct QueryTemplate(Vec<QueryParam>);
+
+impl Default for QueryTemplate {
+    /// The adM Tiles API v1.0 parameters
+    fn default() -> Self {
+        use QueryAttribute::*;
+        Self(vec![
+            QueryParam::attribute("partner", PartnerId),
+            QueryParam::attribute("sub1", Sub1),
+            QueryParam::value("sub2", "newtab"),
+            QueryParam::attribute("country-code", CountryCode),
+            QueryParam::attribute("region-code", RegionCode),
+            QueryParam::attribute("dma-code", DmaCode),
+            QueryParam::attribute("form-factor", FormFactor),
+            QueryParam::attribute("os-family", OsFamily),
+            QueryParam::value("v", "1.0"),
+            // not technically needed, but added for paranoid reasons.
+            QueryParam::value("out", "json"),
+            // XXX: some value for results seems required, it defaults to 0
+            // when omitted (despite AdM claiming it would default to 1)
+            QueryParam::attribute("results", Results),
+        ])
+    }
+}
+
+impl TryFrom<Vec<QueryParam>> for QueryTemplate {
+    type Error = ConfigError;
+
+    fn try_from(params: Vec<QueryParam>) -> Result<Self, Self::Error> {
+        for param in &params {
+            if param.name.is_empty() {
+                return Err(ConfigError::Message(
+                    "Query parameter missing a name".to_owned(),
+                ));
+            }
+            if param.attribute.is_some() == param.value.is_some() {
+                return Err(ConfigError::Message(format!(
+                    "Query parameter {:?} requires exactly one of attribute or value",
+                    param.name
+                )));
+            }
+        }
+        Ok(Self(params))
+    }
+}
+
+impl TryFrom<&str> for QueryTemplate {
+    type Error = ConfigError;
+
+    fn try_from(template: &str) -> Result<Self, Self::Error> {
+        serde_json::from_str(template)
+            .map_err(|e| ConfigError::Message(format!("Invalid query template: {:?}", e)))
+    }
+}
+
+impl QueryTemplate {
+    /// Build the query parameters, looking up attribute values via
+    /// `attribute_value`
+    pub fn params<F>(&self, attribute_value: F) -> Vec<(&str, String)>
+    where
+        F: Fn(QueryAttribute) -> String,
+    {
+        self.0
+            .iter()
+            .map(|param| {
+                let value = match (param.attribute, &param.value) {
+                    (Some(attribute), _) => attribute_value(attribute),
+                    (None, Some(value)) => value.clone(),
+                    // Prevented by validation
+                    (None, None) => String::new(),
+                };
+                (param.name.as_str(), value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template() {
+        let template = QueryTemplate::try_from(
+            r#"[{"name": "cc", "attribute": "country_code"},
+                {"name": "placement", "value": "newtab"}]"#,
+        )
+        .unwrap();
+        let params = template.params(|attribute| match attribute {
+            QueryAttribute::CountryCode => "US".to_owned(),
+            _ => "".to_owned(),
+        });
+        assert_eq!(
+            params,
+            vec![("cc", "US".to_owned()), ("placement", "newtab".to_owned())]
+        );
+    }
+
+    #[test]
+    fn invalid_template() {
+        // Unknown attribute
+        assert!(QueryTemplate::try_from(r#"[{"name": "x", "attribute": "zip_code"}]"#).is_err());
+        // Both (or neither) an attribute and a value
+        assert!(
+            QueryTemplate::try_from(r#"[{"name": "x", "attribute": "sub1", "value": "y"}]"#)
+                .is_err()
+        );
+        assert!(QueryTemplate::try_from(r#"[{"name": "x"}]"#).is_err());
+        // Missing name
+        assert!(QueryTemplate::try_from(r#"[{"name": "", "value": "y"}]"#).is_err());
+    }
+}
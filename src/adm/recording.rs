@@ -0,0 +1,166 @@
+//! Recordings of partner responses, written in `TestModes::Record` and
+//! served in `TestModes::Replay`.
+//!
+//! Recordings are stored under `test_recording_path` in a directory per
+//! partner, named after the audience sent to the partner, e.g.
+//! `adm/US_WA_819_desktop_windows.json`. The partner's secrets (its
+//! `partner_id` and `sub1`) are redacted from them.
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+use super::{tiles::AdmTileResponse, AdmPse};
+use crate::error::{HandlerError, HandlerErrorKind, HandlerResult};
+
+/// Replaces the partner's secrets in recordings
+const REDACTED: &str = "REDACTED";
+
+/// A recorded partner response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Recording {
+    /// The (redacted) request URL
+    pub url: String,
+    /// When the response was recorded (RFC 3339)
+    pub recorded: String,
+    /// The raw (redacted) response body
+    pub response: Value,
+}
+
+/// The path of the recording for a partner and audience
+pub fn recording_path(base: &str, partner: &str, audience: &[String]) -> PathBuf {
+    let name: Vec<_> = audience
+        .iter()
+        .map(|value| {
+            let mut value = value.clone();
+            value.retain(|x| char::is_alphanumeric(x) || x == '-');
+            if value.is_empty() {
+                "none".to_owned()
+            } else {
+                value
+            }
+        })
+        .collect();
+    let mut partner = partner.to_owned();
+    partner.retain(|x| char::is_alphanumeric(x) || x == '_' || x == '-');
+    Path::new(base)
+        .join(partner)
+        .join(format!("{}.json", name.join("_")))
+}
+
+/// Write a redacted recording of a partner response
+pub fn record(path: &Path, pse: &AdmPse, url: &str, mut response: Value) -> HandlerResult<()> {
+    let secrets = [pse.partner_id.as_str(), pse.sub1.as_str()];
+    redact(&mut response, &secrets);
+    let recording = Recording {
+        url: redact_url(url, &secrets).unwrap_or_else(|| REDACTED.to_owned()),
+        recorded: chrono::Utc::now().to_rfc3339(),
+        response,
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    }
+    let file = File::create(path).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    serde_json::to_writer_pretty(file, &recording)
+        .map_err(|e| HandlerError::internal(&e.to_string()))?;
+    trace!("Recorded: {:?}", path);
+    Ok(())
+}
+
+/// Read the partner response from a recording
+pub fn replay(path: &Path) -> HandlerResult<AdmTileResponse> {
+    if !path.exists() {
+        return Err(HandlerError::internal(&format!(
+            "Missing recording {}",
+            path.to_string_lossy()
+        )));
+    }
+    let file = File::open(path).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    let recording: Recording = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| HandlerError::internal(&e.to_string()))?;
+    trace!("Replaying: {:?}", path);
+    serde_json::from_value(recording.response).map_err(|e| {
+        HandlerErrorKind::BadAdmResponse(format!("ADM provided invalid response: {:?}", e)).into()
+    })
+}
+
+/// Redact the secrets from any URL query strings in a JSON value
+fn redact(value: &mut Value, secrets: &[&str]) {
+    match value {
+        Value::String(string) => {
+            if let Some(redacted) = redact_url(string, secrets) {
+                *string = redacted;
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| redact(value, secrets)),
+        Value::Object(map) => map.values_mut().for_each(|value| redact(value, secrets)),
+        _ => {}
+    }
+}
+
+/// Redact the secrets from a URL's query string. `None` if `url` isn't a URL
+fn redact_url(url: &str, secrets: &[&str]) -> Option<String> {
+    let mut parsed = Url::parse(url).ok()?;
+    if parsed.query().is_none() {
+        return Some(url.to_owned());
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(key, value)| {
+            let value = if !value.is_empty() && secrets.contains(&value.as_ref()) {
+                REDACTED.to_owned()
+            } else {
+                value.into_owned()
+            };
+            (key.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    Some(parsed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn redacts_secrets() {
+        let mut response = json!({
+            "tiles": [{
+                "name": "Acme",
+                "click_url": "https://example.com/ctp?partner=demofeed&sub1=123456&ci=6.2"
+            }]
+        });
+        redact(&mut response, &["demofeed", "123456"]);
+        assert_eq!(
+            response["tiles"][0]["click_url"],
+            "https://example.com/ctp?partner=REDACTED&sub1=REDACTED&ci=6.2"
+        );
+        assert_eq!(response["tiles"][0]["name"], "Acme");
+    }
+
+    #[test]
+    fn paths() {
+        let path = recording_path(
+            "/tmp/recordings",
+            "adm",
+            &[
+                "US".to_owned(),
+                "".to_owned(),
+                "../819".to_owned(),
+                "desktop".to_owned(),
+            ],
+        );
+        assert_eq!(
+            path,
+            PathBuf::from("/tmp/recordings/adm/US_none_819_desktop.json")
+        );
+    }
+}
@@ -10,11 +10,12 @@ use std::{
 use config::ConfigError;
 use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::AdmFilter;
+use super::{AdmFilter, QueryTemplate};
 use crate::{
     error::{HandlerError, HandlerResult},
+    providers::PartnerSettings,
     settings::Settings,
-    web::DeviceInfo,
+    web::{DeviceInfo, FormFactor, OsFamily},
 };
 
 /// The name of the "Default" node, which is used as a fall back if no data
@@ -57,13 +58,13 @@ pub(crate) const DEFAULT: &str = "DEFAULT";
 ///     matches. In particular, when loading filters from the settings file,
 ///     Contile will panic if it detects that a prefix filter doesn't have
 ///     the trailing '/' in the `"value"`.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct AdvertiserUrlFilter {
     pub(crate) host: String,
     pub(crate) paths: Option<Vec<PathFilter>>,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PathMatching {
     Prefix,
@@ -96,7 +97,7 @@ impl From<PathMatching> for &'static str {
 
 /// PathFilter describes how path filtering is conducted. See more details in
 /// AdvertiserUrlFilter.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct PathFilter {
     pub(crate) value: String,
     pub(crate) matching: PathMatching,
@@ -110,7 +111,7 @@ pub struct PathFilter {
 /// information that may be used as a DEFAULT, or commonly appearing set
 /// of data. Any Optional value that is not defined will use the value
 /// defined in DEFAULT.
-#[derive(Clone, Debug, Deserialize, Default, Serialize)]
+#[derive(Clone, Debug, Deserialize, Default, PartialEq, Serialize)]
 pub struct AdmAdvertiserFilterSettings {
     /// Required set of valid hosts and paths for the `advertiser_url`
     #[serde(default)]
@@ -142,7 +143,8 @@ pub struct AdmAdvertiserFilterSettings {
     #[serde(default)]
     pub(crate) include_regions: Vec<String>,
     pub(crate) ignore_advertisers: Option<Vec<String>>,
-    pub(crate) ignore_dmas: Option<Vec<u8>>,
+    /// Optional set of DMA codes (500-900) that should not receive the tile
+    pub(crate) ignore_dmas: Option<Vec<u16>>,
     #[serde(default)]
     pub(crate) delete: bool,
 }
@@ -182,51 +184,163 @@ where
     seq.end()
 }
 
+/// A rule selecting the partner_id/sub1/endpoint (and query template) for
+/// the requests it matches.
+///
+/// Rules are specified as an ordered JSON list (the first matching rule
+/// wins), e.g.
+///
+/// ```json
+/// [
+///     {"os_families": ["ios"], "partner_id": "ios-feed", "sub1": "123"},
+///     {"form_factors": ["tablet"], "countries": ["US", "CA"],
+///      "min_version": 100, "endpoint_url": "https://tablet.example.com/v1"}
+/// ]
+/// ```
+///
+/// Omitted criteria match everything. Omitted values fall back to the
+/// partner's mobile or default values.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PseRule {
+    /// Match any of these form factors
+    #[serde(default)]
+    pub form_factors: Vec<FormFactor>,
+    /// Match any of these OS families
+    #[serde(default)]
+    pub os_families: Vec<OsFamily>,
+    /// Match any of these countries (e.g ["US", "GB"])
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Match Firefox versions from this major version (inclusive)
+    pub min_version: Option<u32>,
+    /// Match Firefox versions up to this major version (inclusive)
+    pub max_version: Option<u32>,
+    pub partner_id: Option<String>,
+    pub sub1: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub query: Option<QueryTemplate>,
+}
+
+impl PseRule {
+    /// Read an ordered list of rules from a JSON string
+    pub fn list_from_str(rules: &str) -> Result<Vec<Self>, ConfigError> {
+        let rules: Vec<Self> = serde_json::from_str(rules)
+            .map_err(|e| ConfigError::Message(format!("Invalid partner rules: {:?}", e)))?;
+        Self::validate(&rules)?;
+        Ok(rules)
+    }
+
+    /// Validate an ordered list of rules
+    pub fn validate(rules: &[Self]) -> Result<(), ConfigError> {
+        for (i, rule) in rules.iter().enumerate() {
+            if rule
+                .countries
+                .iter()
+                .any(|country| country != &country.to_uppercase())
+            {
+                return Err(ConfigError::Message(format!(
+                    "Partner rule {} countries must be uppercase",
+                    i
+                )));
+            }
+            if let (Some(min), Some(max)) = (rule.min_version, rule.max_version) {
+                if min > max {
+                    return Err(ConfigError::Message(format!(
+                        "Partner rule {} min_version is greater than max_version",
+                        i
+                    )));
+                }
+            }
+            if matches!(&rule.endpoint_url, Some(url) if url.is_empty()) {
+                return Err(ConfigError::Message(format!(
+                    "Partner rule {} has an empty endpoint_url",
+                    i
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this rule matches the request
+    pub fn matches(&self, device_info: &DeviceInfo, country: &str) -> bool {
+        (self.form_factors.is_empty() || self.form_factors.contains(&device_info.form_factor))
+            && (self.os_families.is_empty() || self.os_families.contains(&device_info.os_family))
+            && (self.countries.is_empty() || self.countries.iter().any(|c| c == country))
+            && self
+                .min_version
+                .map_or(true, |min| device_info.ff_version >= min)
+            && self
+                .max_version
+                .map_or(true, |max| device_info.ff_version <= max)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct AdmPse {
     pub partner_id: String,
     pub sub1: String,
     pub endpoint: String,
+    pub query: QueryTemplate,
 }
 
-/// ADM Partner/Sub1/Endpoint.
-/// These change depending on the type of device requesting the tile.
-///
-/// Currently, we only need to check for two patterns "mobile" and "default",
-/// but there's no guarantee that will always be the case. Hopefully this
-/// pattern provides flexibility for future changes.
+/// ADM Partner/Sub1/Endpoint (and query template).
+/// These change depending on the type of device requesting the tile (and
+/// the partner's [PseRule]s).
 impl AdmPse {
     /// Return the information for a mobile connection
-    pub fn mobile_from_settings(settings: &Settings) -> Self {
-        let default = Self::default_from_settings(settings);
+    pub fn mobile_from_settings(partner: &PartnerSettings) -> Self {
+        let default = Self::default_from_settings(partner);
         AdmPse {
-            partner_id: settings
-                .adm_mobile_partner_id
+            partner_id: partner
+                .mobile_partner_id
                 .clone()
                 .unwrap_or(default.partner_id),
-            sub1: settings.adm_mobile_sub1.clone().unwrap_or(default.sub1),
-            endpoint: settings
-                .adm_mobile_endpoint_url
+            sub1: partner.mobile_sub1.clone().unwrap_or(default.sub1),
+            endpoint: partner
+                .mobile_endpoint_url
                 .clone()
                 .unwrap_or(default.endpoint),
+            query: partner.mobile_query.clone().unwrap_or(default.query),
         }
     }
 
     /// Return the information for a generic connection
-    pub fn default_from_settings(settings: &Settings) -> Self {
+    pub fn default_from_settings(partner: &PartnerSettings) -> Self {
         AdmPse {
-            partner_id: settings.adm_partner_id.clone().unwrap_or_default(),
-            sub1: settings.adm_sub1.clone().unwrap_or_default(),
-            endpoint: settings.adm_endpoint_url.clone(),
+            partner_id: partner.partner_id.clone().unwrap_or_default(),
+            sub1: partner.sub1.clone().unwrap_or_default(),
+            endpoint: partner.endpoint_url.clone(),
+            query: partner.query.clone().unwrap_or_default(),
         }
     }
 
-    /// Determine the correct type of information to return based on device info.
-    pub fn appropriate_from_settings(device_info: &DeviceInfo, settings: &Settings) -> Self {
-        if device_info.is_mobile() {
-            return Self::mobile_from_settings(settings);
+    /// Determine the correct type of information to return based on device
+    /// info and country: the first matching rule's, falling back to the
+    /// mobile or generic information.
+    pub fn appropriate_from_settings(
+        device_info: &DeviceInfo,
+        country: &str,
+        partner: &PartnerSettings,
+    ) -> Self {
+        let fallback = if device_info.is_mobile() {
+            Self::mobile_from_settings(partner)
+        } else {
+            Self::default_from_settings(partner)
+        };
+        match partner
+            .rules
+            .iter()
+            .find(|rule| rule.matches(device_info, country))
+        {
+            Some(rule) => AdmPse {
+                partner_id: rule.partner_id.clone().unwrap_or(fallback.partner_id),
+                sub1: rule.sub1.clone().unwrap_or(fallback.sub1),
+                endpoint: rule.endpoint_url.clone().unwrap_or(fallback.endpoint),
+                query: rule.query.clone().unwrap_or(fallback.query),
+            },
+            None => fallback,
         }
-        Self::default_from_settings(settings)
     }
 }
 
@@ -286,6 +400,17 @@ impl TryFrom<String> for AdmFilterSettings {
             }) {
                 return Err(ConfigError::Message(format!("Advertiser {:?} advertiser_urls contain invalid prefix PathFilter (missing trailing '/')", adv)));
             }
+            if let Some(dma) = filter_setting
+                .ignore_dmas
+                .iter()
+                .flatten()
+                .find(|dma| !(500..=900).contains(*dma))
+            {
+                return Err(ConfigError::Message(format!(
+                    "Advertiser {:?} ignore_dmas contains invalid DMA code {}",
+                    adv, dma
+                )));
+            }
         }
         Ok(AdmFilterSettings {
             advertisers: adm_settings,
@@ -386,6 +511,9 @@ impl TryFrom<&mut Settings> for AdmFilterSettings {
 ///     /* Valid target countries for this advertiser
 ///        TODO: could support country + subdivision, e.g. "USOK" */
 ///     "include_regions": ["US", "MX"],
+///     /* DMA codes that should never receive this advertiser's tiles.
+///        Empty means to use the DMA codes in "DEFAULT" */
+///     "ignore_dmas": [819],
 ///     /* Allowed hosts for impression URLs.
 ///        Empty means to use the impression URLs in "DEFAULT" */
 ///     "impression_hosts: [],
@@ -416,6 +544,7 @@ impl From<&mut Settings> for HandlerResult<AdmFilter> {
         let source = settings.adm_settings.clone();
         let connect_timeout = settings.connect_timeout;
         let request_timeout = settings.request_timeout;
+        let position_precedence = settings.position_precedence;
         let source_url = match source.parse::<url::Url>() {
             Ok(v) => Some(v),
             Err(e) => {
@@ -449,6 +578,7 @@ impl From<&mut Settings> for HandlerResult<AdmFilter> {
             ignore_list,
             all_include_regions,
             legacy_list,
+            position_precedence,
             last_updated: source.starts_with("gs://").then(chrono::Utc::now),
             source,
             source_url,
@@ -527,4 +657,73 @@ mod tests {
         settings.adm_settings = adm_settings.to_owned();
         assert!(AdmFilterSettings::try_from(&mut settings).is_err());
     }
+
+    #[test]
+    pub fn pse_rules() {
+        let partner = PartnerSettings {
+            endpoint_url: "https://example.com/".to_owned(),
+            partner_id: Some("default".to_owned()),
+            mobile_partner_id: Some("mobile".to_owned()),
+            rules: PseRule::list_from_str(
+                r#"[{"os_families": ["ios"], "partner_id": "ios"},
+                    {"form_factors": ["tablet"], "countries": ["CA"],
+                     "min_version": 100, "sub1": "tablet"}]"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let device_info = |form_factor, os_family, ff_version| DeviceInfo {
+            form_factor,
+            os_family,
+            ff_version,
+        };
+
+        let ios = device_info(FormFactor::Phone, OsFamily::IOs, 100);
+        let pse = AdmPse::appropriate_from_settings(&ios, "US", &partner);
+        assert_eq!(pse.partner_id, "ios");
+
+        let tablet = device_info(FormFactor::Tablet, OsFamily::Android, 100);
+        let pse = AdmPse::appropriate_from_settings(&tablet, "CA", &partner);
+        assert_eq!(pse.sub1, "tablet");
+        // Falls back to the mobile values
+        assert_eq!(pse.partner_id, "mobile");
+        assert_eq!(pse.endpoint, "https://example.com/");
+
+        // Wrong market/version
+        let pse = AdmPse::appropriate_from_settings(&tablet, "US", &partner);
+        assert_eq!(pse.sub1, "");
+        let old_tablet = device_info(FormFactor::Tablet, OsFamily::Android, 99);
+        let pse = AdmPse::appropriate_from_settings(&old_tablet, "CA", &partner);
+        assert_eq!(pse.sub1, "");
+
+        let desktop = device_info(FormFactor::Desktop, OsFamily::Windows, 100);
+        let pse = AdmPse::appropriate_from_settings(&desktop, "CA", &partner);
+        assert_eq!(pse.partner_id, "default");
+    }
+
+    #[test]
+    pub fn invalid_pse_rules() {
+        assert!(PseRule::list_from_str(r#"[{"os_families": ["beos"]}]"#).is_err());
+        assert!(PseRule::list_from_str(r#"[{"countries": ["us"]}]"#).is_err());
+        assert!(PseRule::list_from_str(r#"[{"min_version": 91, "max_version": 90}]"#).is_err());
+        assert!(PseRule::list_from_str(r#"[{"browsers": ["chrome"]}]"#).is_err());
+    }
+
+    #[test]
+    pub fn test_invalid_ignore_dmas() {
+        let mut adm_settings = adm_settings();
+        adm_settings
+            .advertisers
+            .get_mut("Acme")
+            .expect("No Acme tile")
+            .ignore_dmas = Some(vec![819, 1000]);
+        assert!(AdmFilterSettings::try_from(json!(adm_settings).to_string()).is_err());
+
+        adm_settings
+            .advertisers
+            .get_mut("Acme")
+            .expect("No Acme tile")
+            .ignore_dmas = Some(vec![819, 500, 900]);
+        assert!(AdmFilterSettings::try_from(json!(adm_settings).to_string()).is_ok());
+    }
 }
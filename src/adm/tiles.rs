@@ -2,26 +2,31 @@ use std::{
     fmt::Debug,
     fs::File,
     io::BufReader,
-    path::Path,
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
-use actix_http::http::header::{HeaderMap, HeaderValue};
+use actix_http::http::header::HeaderMap;
 use actix_web_location::Location;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::{
-    adm::{AdmPse, DEFAULT},
+    adm::{recording, AdmPse, QueryAttribute, DEFAULT},
     error::{HandlerError, HandlerErrorKind, HandlerResult},
     metrics::Metrics,
-    server::ServerState,
-    settings::Settings,
+    providers::{slot_tiles, PartnerSettings},
+    server::{cache::AudienceKey, ServerState},
+    settings::{Settings, TestModes},
     tags::Tags,
     web::middleware::sentry::report,
     web::DeviceInfo,
 };
 
+/// Matches any value of a component of an audience fixture's path
+const FIXTURE_WILDCARD: &str = "_";
+
 /// The payload provided by ADM
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AdmTileResponse {
@@ -64,6 +69,61 @@ impl AdmTileResponse {
         trace!("Err: {:?}", &err);
         Err(HandlerError::internal(&err))
     }
+
+    /// Return a fake response for an audience, from a directory tree of
+    /// fixtures under `CONTILE_TEST_FILE_PATH`
+    ///
+    /// This is used when the server is in `test_mode` and not passed a
+    /// `fake-response` header. Fixtures are named
+    /// `{country}/{region}/{form_factor}/{os_family}.json` (e.g.
+    /// `US/WA/desktop/windows.json`), where any component may be the `_`
+    /// wildcard (e.g. `US/_/phone/_.json`). The most specific fixture wins,
+    /// with earlier components taking precedence over later ones (so
+    /// `US/WA/_/_.json` is preferred over `US/_/desktop/windows.json`). When
+    /// no fixture matches, the `DEFAULT` response file is used.
+    pub fn audience_fake_response(
+        settings: &Settings,
+        audience_key: &AudienceKey,
+    ) -> HandlerResult<Self> {
+        let mut country = audience_key.country_code.clone();
+        country.retain(char::is_alphanumeric);
+        let mut region = audience_key.region_code.clone().unwrap_or_default();
+        region.retain(char::is_alphanumeric);
+        let components = [
+            country,
+            region,
+            audience_key.form_factor.to_string(),
+            audience_key.os_family.to_string(),
+        ];
+        // Try every combination of the components and wildcards, most
+        // specific first: the bits of `mask` mark the wildcards, in order of
+        // increasing precedence
+        for mask in 0..(1 << components.len()) {
+            let path = components
+                .iter()
+                .enumerate()
+                .fold(
+                    PathBuf::from(&settings.test_file_path),
+                    |path, (i, component)| {
+                        let wildcard = mask & (1 << (components.len() - 1 - i)) != 0;
+                        path.join(if wildcard || component.is_empty() {
+                            FIXTURE_WILDCARD
+                        } else {
+                            component
+                        })
+                    },
+                )
+                .with_extension("json");
+            if path.exists() {
+                trace!("Audience fixture: {:?}", &path);
+                let file = File::open(path.as_os_str())
+                    .map_err(|e| HandlerError::internal(&e.to_string()))?;
+                return serde_json::from_reader(BufReader::new(file))
+                    .map_err(|e| HandlerError::internal(&e.to_string()));
+            }
+        }
+        Self::fake_response(settings, DEFAULT.to_owned())
+    }
 }
 
 /// The individual tile data provided by ADM
@@ -98,6 +158,9 @@ pub struct Tile {
     pub image_url: String,
     pub image_size: Option<u32>,
     pub impression_url: String,
+    /// The slot the UA should place this tile in (assigned by
+    /// [crate::providers::slot_tiles])
+    pub position: Option<u8>,
 }
 
 impl Tile {
@@ -113,6 +176,7 @@ impl Tile {
             image_url: tile.image_url,
             image_size: None,
             impression_url: tile.impression_url,
+            position: tile.position,
         }
     }
 }
@@ -125,47 +189,113 @@ pub fn filtered_dma(exclude: &Option<Vec<u16>>, dma: &u16) -> String {
     }
 }
 
-/// Main handler for the User Agent HTTP request
+/// Send the (idempotent) tiles request to the partner, retrying failed attempts
+/// up to `partner_max_retries` times (rate limits permitting). Each attempt may
+/// be hedged
+async fn send_with_retries(
+    state: &ServerState,
+    endpoint: &str,
+    adm_url: &str,
+    timeout: Duration,
+    tags: &Tags,
+    metrics: &Metrics,
+) -> reqwest::Result<reqwest::Response> {
+    let request = || async move {
+        state
+            .reqwest_client
+            .get(adm_url)
+            .timeout(timeout)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+    };
+    let mut attempt = 0;
+    loop {
+        let result = state
+            .hedger
+            .send(
+                endpoint,
+                request,
+                || state.rate_limiter.try_acquire(endpoint),
+                tags,
+                metrics,
+            )
+            .await;
+        match result {
+            Err(e)
+                if attempt < state.settings.partner_max_retries
+                    && is_retryable(&e)
+                    && state.rate_limiter.try_acquire(endpoint) =>
+            {
+                attempt += 1;
+                trace!("adm::get_tiles: retry {} after {:?}", attempt, e);
+                metrics.incr_with_tags("tiles.adm.retry", Some(tags));
+                actix_rt::time::delay_for(retry_delay(
+                    state.settings.partner_retry_backoff_ms,
+                    attempt,
+                ))
+                .await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a failed partner request is worth retrying
+fn is_retryable(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.status().map_or(false, |status| status.is_server_error())
+}
+
+/// Exponential backoff (from `base_ms`) with full jitter
+fn retry_delay(base_ms: u64, attempt: u8) -> Duration {
+    let max = base_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(16));
+    Duration::from_millis(thread_rng().gen_range(0..=max))
+}
+
+/// Fetch and filter tiles from the adM Tiles API of the given partner
 pub async fn get_tiles(
     state: &ServerState,
+    partner: &PartnerSettings,
     location: &Location,
-    device_info: DeviceInfo,
+    device_info: &DeviceInfo,
     tags: &mut Tags,
     metrics: &Metrics,
     headers: Option<&HeaderMap>,
 ) -> HandlerResult<TileResponse> {
     let settings = &state.settings;
     let image_store = &state.img_store;
-    let pse = AdmPse::appropriate_from_settings(&device_info, settings);
-    let adm_url = Url::parse_with_params(
-        &pse.endpoint,
-        &[
-            ("partner", pse.partner_id.as_str()),
-            ("sub1", pse.sub1.as_str()),
-            ("sub2", "newtab"),
-            (
-                "country-code",
-                &(location
-                    .country
-                    .clone()
-                    .unwrap_or_else(|| settings.fallback_country.clone())),
-            ),
-            ("region-code", &location.region()),
-            (
-                "dma-code",
-                &filtered_dma(&state.excluded_dmas, &location.dma()),
-            ),
-            ("form-factor", &device_info.form_factor.to_string()),
-            ("os-family", &device_info.os_family.to_string()),
-            ("v", "1.0"),
-            ("out", "json"), // not technically needed, but added for paranoid reasons.
-            // XXX: some value for results seems required, it defaults to 0
-            // when omitted (despite AdM claiming it would default to 1)
-            ("results", &settings.adm_query_tile_count.to_string()),
-        ],
-    )
-    .map_err(|e| HandlerError::internal(&e.to_string()))?;
+    let country = location
+        .country
+        .clone()
+        .unwrap_or_else(|| settings.fallback_country.clone());
+    let pse = AdmPse::appropriate_from_settings(device_info, &country, partner);
+    let timeout = Duration::from_secs(partner.timeout.unwrap_or(settings.adm_timeout));
+    let attribute_value = |attribute| match attribute {
+        QueryAttribute::PartnerId => pse.partner_id.clone(),
+        QueryAttribute::Sub1 => pse.sub1.clone(),
+        QueryAttribute::CountryCode => country.clone(),
+        QueryAttribute::RegionCode => location.region(),
+        QueryAttribute::DmaCode => filtered_dma(&state.excluded_dmas, &location.dma()),
+        QueryAttribute::FormFactor => device_info.form_factor.to_string(),
+        QueryAttribute::OsFamily => device_info.os_family.to_string(),
+        QueryAttribute::Results => settings.adm_query_tile_count.to_string(),
+    };
+    let adm_url = Url::parse_with_params(&pse.endpoint, pse.query.params(attribute_value))
+        .map_err(|e| HandlerError::internal(&e.to_string()))?;
     let adm_url = adm_url.as_str();
+    let recording_path = || {
+        let audience: Vec<_> = [
+            QueryAttribute::CountryCode,
+            QueryAttribute::RegionCode,
+            QueryAttribute::DmaCode,
+            QueryAttribute::FormFactor,
+            QueryAttribute::OsFamily,
+        ]
+        .iter()
+        .map(|attribute| attribute_value(*attribute))
+        .collect();
+        recording::recording_path(&settings.test_recording_path, &partner.name, &audience)
+    };
 
     // To reduce cardinality, only add this tag when fetching data from
     // the partner. (This tag is only for metrics.)
@@ -178,34 +308,44 @@ pub async fn get_tiles(
     if device_info.is_mobile() {
         tags.add_tag("endpoint", "mobile");
     }
+    tags.add_tag("partner", &partner.name);
     tags.add_extra("adm_url", adm_url);
 
     metrics.incr_with_tags("tiles.adm.request", Some(tags));
     let response: AdmTileResponse = match state.settings.test_mode {
-        crate::settings::TestModes::TestFakeResponse => {
-            let default = HeaderValue::from_str(DEFAULT).unwrap();
-            let test_response = headers
-                .unwrap_or(&HeaderMap::new())
-                .get("fake-response")
-                .unwrap_or(&default)
-                .to_str()
-                .unwrap()
-                .to_owned();
-            trace!("Getting fake response: {:?}", &test_response);
-            AdmTileResponse::fake_response(&state.settings, test_response)?
+        TestModes::TestFakeResponse => {
+            match headers.and_then(|headers| headers.get("fake-response")) {
+                Some(test_response) => {
+                    let test_response = test_response.to_str().unwrap().to_owned();
+                    trace!("Getting fake response: {:?}", &test_response);
+                    AdmTileResponse::fake_response(&state.settings, test_response)?
+                }
+                None => AdmTileResponse::audience_fake_response(
+                    &state.settings,
+                    &AudienceKey::new(location, device_info),
+                )?,
+            }
         }
-        crate::settings::TestModes::TestTimeout => {
+        TestModes::TestTimeout => {
             trace!("### Timeout!");
             return Err(HandlerErrorKind::AdmLoadError().into());
         }
+        TestModes::Replay => recording::replay(&recording_path())?,
         _ => {
-            state
-                .reqwest_client
-                .get(adm_url)
-                .timeout(Duration::from_secs(settings.adm_timeout))
-                .send()
+            // Don't pile onto the partner (e.g. while filling a cold cache)
+            if !state.rate_limiter.try_acquire(&pse.endpoint) {
+                trace!("adm::get_tiles: rate limited {}", &pse.endpoint);
+                metrics.incr_with_tags("tiles.adm.rate_limited", Some(tags));
+                return Err(HandlerErrorKind::PartnerRateLimited(partner.name.clone()).into());
+            }
+            let sent = Instant::now();
+            let response = send_with_retries(state, &pse.endpoint, adm_url, timeout, tags, metrics)
                 .await
                 .map_err(|e| {
+                    if e.is_status() {
+                        // ADM servers responded with an error status
+                        return HandlerError::from(e);
+                    }
                     // If we're just starting up, we're probably swamping the partner servers as
                     // we fill the queue. Instead of returning a normal 500 error, let's
                     // return something softer to keep our SRE's blood pressure lower.
@@ -214,10 +354,10 @@ pub async fn get_tiles(
                     //
                     // TODO: Remove this after the shared cache is implemented.
                     let mut err: HandlerError = if e.is_timeout()
-                        && Instant::now()
+                        && sent
                             .checked_duration_since(state.start_up)
                             .unwrap_or_else(|| Duration::from_secs(0))
-                            <= Duration::from_secs(state.settings.adm_timeout)
+                            <= timeout
                     {
                         HandlerErrorKind::AdmLoadError().into()
                     } else {
@@ -226,17 +366,21 @@ pub async fn get_tiles(
                     // ADM servers are down, or improperly configured
                     err.tags.add_extra("error", &e.to_string());
                     err
-                })?
-                .error_for_status()?
-                .json()
-                .await
-                .map_err(|e| {
-                    // ADM servers are not returning correct information
-                    HandlerErrorKind::BadAdmResponse(format!(
-                        "ADM provided invalid response: {:?}",
-                        e
-                    ))
-                })?
+                })?;
+            // ADM servers are not returning correct information
+            let bad_response = |e: &dyn Debug| {
+                HandlerErrorKind::BadAdmResponse(format!("ADM provided invalid response: {:?}", e))
+            };
+            if settings.test_mode == TestModes::Record {
+                let body: serde_json::Value =
+                    response.json().await.map_err(|e| bad_response(&e))?;
+                if let Err(e) = recording::record(&recording_path(), &pse, adm_url, body.clone()) {
+                    warn!("Couldn't record response: {:?}", e);
+                }
+                serde_json::from_value(body).map_err(|e| bad_response(&e))?
+            } else {
+                response.json().await.map_err(|e| bad_response(&e))?
+            }
         }
     };
     if response.tiles.is_empty() {
@@ -251,13 +395,16 @@ pub async fn get_tiles(
             state.filter.read().unwrap().filter_and_process(
                 tile,
                 location,
-                &device_info,
+                device_info,
                 tags,
                 metrics,
             )
         })
-        .take(settings.adm_max_tiles as usize)
         .collect();
+    // Slot by position before capping, so positioned tiles aren't crowded out
+    let filtered = slot_tiles(filtered)
+        .into_iter()
+        .take(partner.max_tiles.unwrap_or(settings.adm_max_tiles) as usize);
 
     let mut tiles: Vec<Tile> = Vec::new();
     for mut tile in filtered {
@@ -307,4 +454,13 @@ mod test {
         assert_eq!(filtered_dma(&excluded_dmas, &0), "".to_owned());
         assert_eq!(filtered_dma(&excluded_dmas, &200), "200".to_owned());
     }
+
+    #[test]
+    fn test_retry_delay() {
+        for attempt in 1..=3 {
+            let max = Duration::from_millis(50 * (1 << (attempt - 1)));
+            assert!(retry_delay(50, attempt) <= max);
+        }
+        assert_eq!(retry_delay(0, 1), Duration::from_millis(0));
+    }
 }
@@ -0,0 +1,73 @@
+//! A mock adM partner server for local development
+#![forbid(unsafe_code)]
+use std::error::Error;
+
+#[macro_use]
+extern crate slog_scope;
+
+use actix_web::{App, HttpServer};
+use docopt::Docopt;
+use serde::Deserialize;
+
+use contile::logging::init_logging;
+
+mod mock_partner;
+
+const USAGE: &str = "
+Usage: contile-mock-partner [options]
+
+Serves the adM tiles API (at /tilesp/desktop and /tilesp/mobile) from the
+responses.yml files in the responses directory.
+
+Options:
+    -h, --help                   Show this message.
+    --host=HOST                  Host to listen on [default: 127.0.0.1].
+    --port=PORT                  Port to listen on [default: 5000].
+    --responses-dir=DIR          Directory of response files [default: ./integration-tests/volumes/partner].
+    --mobile-form-factors=LIST   Form factors accepted by /tilesp/mobile [default: phone,tablet].
+    --desktop-form-factors=LIST  Form factors accepted by /tilesp/desktop [default: desktop].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_host: String,
+    flag_port: u16,
+    flag_responses_dir: String,
+    flag_mobile_form_factors: String,
+    flag_desktop_form_factors: String,
+}
+
+fn split(form_factors: &str) -> Vec<String> {
+    form_factors
+        .split(',')
+        .map(|form_factor| form_factor.trim().to_owned())
+        .filter(|form_factor| !form_factor.is_empty())
+        .collect()
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    init_logging(false).expect("Logging failed to init");
+
+    let partner = mock_partner::MockPartner {
+        mobile_form_factors: split(&args.flag_mobile_form_factors),
+        desktop_form_factors: split(&args.flag_desktop_form_factors),
+        ..mock_partner::MockPartner::new(&args.flag_responses_dir)
+    };
+    info!(
+        "Mock partner serving {} on {}:{}",
+        args.flag_responses_dir, args.flag_host, args.flag_port
+    );
+    HttpServer::new(move || {
+        App::new()
+            .data(partner.clone())
+            .configure(mock_partner::service)
+    })
+    .bind((args.flag_host.as_str(), args.flag_port))?
+    .run()
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! A mock adM partner, serving canned tiles API responses
+//!
+//! Lets the `contile-mock-partner` binary run a realistic partner locally. Responses are read (on every request, so
+//! they may be edited while running) from a directory of YAML or JSON files
+//! in the format used by the integration tests' partner:
+//!
+//! ```yaml
+//! desktop:          # form-factor
+//!   windows:        # os-family
+//!     status_code: 200
+//!     delay: 0.5    # seconds
+//!     headers:
+//!       - name: X-Example
+//!         value: example
+//!     content:      # JSON body (or a string, sent as is, e.g. a malformed body)
+//!       tiles:
+//!         - id: 12345
+//!           ...
+//! ```
+//!
+//! The most specific file for the request's country-code and region-code
+//! is used: `{dir}/{country}/{region}.yml`, then `{dir}/{country}/responses.yml`
+//! and finally `{dir}/responses.yml` (`.yaml` and `.json` extensions are
+//! also accepted).
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use config::{Config, ConfigError, File};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// File extensions of the response files, in order of preference
+const EXTENSIONS: &[&str] = &["yml", "yaml", "json"];
+
+/// A canned partner response
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockResponse {
+    #[serde(default = "default_status_code")]
+    pub status_code: u16,
+    #[serde(default)]
+    pub headers: Vec<MockHeader>,
+    /// The response body: JSON, or a string sent as is. Empty when omitted
+    #[serde(default)]
+    pub content: Option<Value>,
+    /// Delay the response by this many seconds
+    #[serde(default)]
+    pub delay: f64,
+}
+
+fn default_status_code() -> u16 {
+    200
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// Responses by form-factor, then os-family
+type MockResponses = HashMap<String, HashMap<String, MockResponse>>;
+
+/// The mock partner's configuration
+#[derive(Clone, Debug)]
+pub struct MockPartner {
+    /// The directory of response files
+    pub responses_dir: PathBuf,
+    /// Form factors accepted by the `/tilesp/mobile` endpoint
+    pub mobile_form_factors: Vec<String>,
+    /// Form factors accepted by the `/tilesp/desktop` endpoint
+    pub desktop_form_factors: Vec<String>,
+}
+
+impl MockPartner {
+    pub fn new(responses_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            responses_dir: responses_dir.into(),
+            mobile_form_factors: vec!["phone".to_owned(), "tablet".to_owned()],
+            desktop_form_factors: vec!["desktop".to_owned()],
+        }
+    }
+
+    /// The most specific response file for a country and region
+    fn responses_path(&self, country: &str, region: &str) -> Option<PathBuf> {
+        let mut candidates = Vec::new();
+        if !country.is_empty() {
+            let country_dir = self.responses_dir.join(country);
+            if !region.is_empty() {
+                candidates.push(country_dir.join(region));
+            }
+            candidates.push(country_dir.join("responses"));
+        }
+        candidates.push(self.responses_dir.join("responses"));
+        candidates.iter().find_map(|candidate| {
+            EXTENSIONS
+                .iter()
+                .map(|ext| candidate.with_extension(ext))
+                .find(|path| path.is_file())
+        })
+    }
+
+    /// Find the response for an audience
+    pub fn response(
+        &self,
+        country: &str,
+        region: &str,
+        form_factor: &str,
+        os_family: &str,
+    ) -> Result<Option<MockResponse>, ConfigError> {
+        // Don't allow wandering outside of `responses_dir`
+        let valid = |s: &str| s.chars().all(char::is_alphanumeric);
+        if !valid(country) || !valid(region) {
+            return Ok(None);
+        }
+        let path = match self.responses_path(country, region) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let mut responses = load(&path)?;
+        Ok(responses
+            .remove(form_factor)
+            .and_then(|mut by_os| by_os.remove(os_family)))
+    }
+}
+
+/// Read a response file
+fn load(path: &Path) -> Result<MockResponses, ConfigError> {
+    trace!("mock_partner: loading {:?}", path);
+    let mut config = Config::new();
+    config.merge(File::from(path))?;
+    config.try_into()
+}
+
+/// Register the mock partner's endpoints (requires [MockPartner] app data)
+pub fn service(config: &mut web::ServiceConfig) {
+    config.service(web::resource("/tilesp/{endpoint}").route(web::get().to(tilesp)));
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "error": message }))
+}
+
+/// Handler for the adM tiles API
+async fn tilesp(
+    endpoint: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    partner: web::Data<MockPartner>,
+    _request: HttpRequest,
+) -> HttpResponse {
+    let param = |name: &str| query.get(name).map(String::as_str).unwrap_or_default();
+    for required in &[
+        "partner",
+        "sub1",
+        "country-code",
+        "form-factor",
+        "os-family",
+    ] {
+        if param(required).is_empty() {
+            return bad_request(&format!("Missing {}", required));
+        }
+    }
+    let form_factor = param("form-factor");
+    let accepted = match endpoint.as_str() {
+        "desktop" => &partner.desktop_form_factors,
+        "mobile" => &partner.mobile_form_factors,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    if !accepted.iter().any(|accepted| accepted == form_factor) {
+        return bad_request(&format!(
+            "Form factor {:?} not accepted by the {} endpoint",
+            form_factor,
+            endpoint.as_str()
+        ));
+    }
+
+    let response = match partner.response(
+        param("country-code"),
+        param("region-code"),
+        form_factor,
+        param("os-family"),
+    ) {
+        Ok(Some(response)) => response,
+        Ok(None) => return bad_request("No response configured for this audience"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({ "error": format!("Invalid responses: {}", e) }))
+        }
+    };
+
+    if response.delay > 0.0 {
+        actix_rt::time::delay_for(Duration::from_secs_f64(response.delay)).await;
+    }
+    let status =
+        StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut builder = HttpResponse::build(status);
+    for header in &response.headers {
+        builder.header(header.name.as_str(), header.value.as_str());
+    }
+    match response.content {
+        // Sent as is: e.g. a malformed body
+        Some(Value::String(body)) => builder.body(body),
+        Some(content) => builder.json(content),
+        None => builder.finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+
+    use super::*;
+
+    fn tilesp(endpoint: &str, region_code: &str) -> actix_http::Request {
+        test::TestRequest::get()
+            .uri(&format!(
+                "/tilesp/{}?partner=foo&sub1=bar&country-code=US&region-code={}\
+                 &form-factor=desktop&os-family=windows",
+                endpoint, region_code
+            ))
+            .to_request()
+    }
+
+    #[actix_rt::test]
+    async fn responses() {
+        let partner = MockPartner::new("integration-tests/volumes/partner");
+        let mut app = test::init_service(App::new().data(partner).configure(service)).await;
+
+        for (region_code, expected) in &[
+            // US/WA.yml
+            ("WA", "https://www.example.com/us_wa_desktop_windows"),
+            // Falls back to responses.yml
+            ("CA", "https://www.example.com/desktop_windows"),
+        ] {
+            let resp = test::call_service(&mut app, tilesp("desktop", region_code)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let result: Value = test::read_body_json(resp).await;
+            let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+            assert_eq!(tiles.len(), 2);
+            assert_eq!(&tiles[0]["name"], "Example COM");
+            assert_eq!(&tiles[0]["advertiser_url"], expected);
+        }
+
+        // Desktop form factors aren't accepted by the mobile endpoint
+        let resp = test::call_service(&mut app, tilesp("mobile", "WA")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -9,7 +9,7 @@ use actix_web::http::uri::InvalidUri;
 use actix_web::{
     dev::{HttpResponseBuilder, ServiceResponse},
     error::ResponseError,
-    http::StatusCode,
+    http::{header, StatusCode},
     middleware::errhandlers::ErrorHandlerResponse,
     HttpResponse, Result,
 };
@@ -84,10 +84,22 @@ pub enum HandlerErrorKind {
     #[error("Adm Cache Load Error")]
     AdmLoadError(),
 
+    /// The partner's circuit breaker is open
+    #[error("Partner unavailable: {:?}", _0)]
+    PartnerUnavailable(String),
+
+    /// No outbound request token was available for the partner
+    #[error("Partner rate limited: {:?}", _0)]
+    PartnerRateLimited(String),
+
     /// Invalid UserAgent request
     #[error("Invalid user agent")]
     InvalidUA,
 
+    /// A request to the admin API lacked a valid bearer token
+    #[error("Unauthorized")]
+    Unauthorized,
+
     #[error("Cloud Storage error: {}", _0)]
     CloudStorage(#[from] cloud_storage::Error),
 }
@@ -99,13 +111,16 @@ impl HandlerErrorKind {
         match self {
             HandlerErrorKind::Validation(_) => StatusCode::BAD_REQUEST,
             HandlerErrorKind::AdmServerError() => StatusCode::SERVICE_UNAVAILABLE,
-            HandlerErrorKind::AdmLoadError() => StatusCode::NO_CONTENT,
+            HandlerErrorKind::AdmLoadError()
+            | HandlerErrorKind::PartnerUnavailable(_)
+            | HandlerErrorKind::PartnerRateLimited(_) => StatusCode::NO_CONTENT,
             HandlerErrorKind::BadAdmResponse(_)
             | HandlerErrorKind::InvalidHost(_, _)
             | HandlerErrorKind::UnexpectedHost(_, _)
             | HandlerErrorKind::BadImage(_)
             | HandlerErrorKind::CloudStorage(_) => StatusCode::BAD_GATEWAY,
             &HandlerErrorKind::InvalidUA => StatusCode::FORBIDDEN,
+            HandlerErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -119,6 +134,8 @@ impl HandlerErrorKind {
             HandlerErrorKind::BadAdmResponse(_) => 521,
             HandlerErrorKind::AdmServerError() => 522,
             HandlerErrorKind::AdmLoadError() => 523,
+            HandlerErrorKind::PartnerUnavailable(_) => 524,
+            HandlerErrorKind::PartnerRateLimited(_) => 525,
             HandlerErrorKind::Location(_) => 530,
             HandlerErrorKind::Validation(_) => 600,
             HandlerErrorKind::InvalidHost(_, _) => 601,
@@ -128,6 +145,7 @@ impl HandlerErrorKind {
             HandlerErrorKind::BadImage(_) => 605,
             HandlerErrorKind::CloudStorage(_) => 620,
             HandlerErrorKind::InvalidUA => 700,
+            HandlerErrorKind::Unauthorized => 701,
         }
     }
 
@@ -136,13 +154,34 @@ impl HandlerErrorKind {
     pub fn metric_label(&self) -> Option<&'static str> {
         match self {
             HandlerErrorKind::InvalidUA => Some("request.error.invalid_ua"),
+            HandlerErrorKind::Unauthorized => Some("request.error.unauthorized"),
+            HandlerErrorKind::PartnerUnavailable(_) => Some("request.error.partner_unavailable"),
+            HandlerErrorKind::PartnerRateLimited(_) => Some("request.error.partner_rate_limited"),
             _ => None,
         }
     }
 
     /// Whether this error should trigger a Sentry event
     pub fn is_sentry_event(&self) -> bool {
-        !matches!(self, HandlerErrorKind::InvalidUA)
+        !matches!(
+            self,
+            HandlerErrorKind::InvalidUA
+                | HandlerErrorKind::Unauthorized
+                | HandlerErrorKind::PartnerUnavailable(_)
+                | HandlerErrorKind::PartnerRateLimited(_)
+        )
+    }
+
+    /// Whether this error indicates the partner is failing (counted by its
+    /// circuit breaker)
+    pub fn is_partner_failure(&self) -> bool {
+        matches!(
+            self,
+            HandlerErrorKind::Reqwest(_)
+                | HandlerErrorKind::BadAdmResponse(_)
+                | HandlerErrorKind::AdmServerError()
+                | HandlerErrorKind::AdmLoadError()
+        )
     }
 
     pub fn as_response_string(&self) -> String {
@@ -162,9 +201,13 @@ impl HandlerErrorKind {
             | HandlerErrorKind::BadImage(_) => {
                 "An invalid response received from the partner".to_string()
             }
+            HandlerErrorKind::PartnerUnavailable(_) | HandlerErrorKind::PartnerRateLimited(_) => {
+                "The partner is currently unavailable".to_string()
+            }
             HandlerErrorKind::Location(_) => self.to_string(),
             HandlerErrorKind::CloudStorage(_) => "Could not cache an tile image".to_string(),
             HandlerErrorKind::InvalidUA => "This service is for firefox only".to_string(),
+            HandlerErrorKind::Unauthorized => self.to_string(),
         }
     }
 }
@@ -237,6 +280,9 @@ impl fmt::Display for HandlerError {
 impl ResponseError for HandlerError {
     fn error_response(&self) -> HttpResponse {
         let mut resp = HttpResponse::build(self.status_code());
+        if let HandlerErrorKind::Unauthorized = self.kind() {
+            resp.header(header::WWW_AUTHENTICATE, "Bearer");
+        }
         resp.json(json!({
             "code": self.kind().http_status().as_u16(),
             "errno": self.kind().errno(),
@@ -19,6 +19,7 @@ pub mod adm;
 pub mod logging;
 pub mod error;
 pub mod metrics;
+pub mod providers;
 pub mod server;
 pub mod settings;
 pub mod tags;
@@ -0,0 +1,266 @@
+//! Per partner circuit breaker
+//!
+//! Stops sending requests to a partner that's repeatedly failing, giving it
+//! time to recover. After `partner_breaker_failures` failures within
+//! `partner_breaker_window_secs` the breaker opens and requests fail fast.
+//! After `partner_breaker_open_secs` a single probe request is let through
+//! (half open): its success closes the breaker, its failure reopens it.
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{HandlerErrorKind, HandlerResult},
+    metrics::Metrics,
+    settings::Settings,
+    tags::Tags,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakerState {
+    /// Requests flow normally
+    Closed,
+    /// Requests fail fast
+    Open,
+    /// A probe request is allowed through to test the partner
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Closed => "closed",
+                Self::Open => "open",
+                Self::HalfOpen => "half_open",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    /// Times of the recent failures (within the window)
+    failures: VecDeque<Instant>,
+    opened_at: Option<Instant>,
+    /// When the current half open probe was let through
+    probe_started: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// The partner's name
+    name: String,
+    /// Number of failures before opening (0 disables the breaker)
+    threshold: usize,
+    window: Duration,
+    open_duration: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, settings: &Settings) -> Self {
+        Self {
+            name: name.to_owned(),
+            threshold: settings.partner_breaker_failures as usize,
+            window: Duration::from_secs(settings.partner_breaker_window_secs),
+            open_duration: Duration::from_secs(settings.partner_breaker_open_secs),
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: VecDeque::new(),
+                opened_at: None,
+                probe_started: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Determine whether a request to the partner should be attempted
+    pub fn allow(&self, metrics: &Metrics) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let allowed = match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => {
+                if inner
+                    .opened_at
+                    .map_or(true, |at| at.elapsed() >= self.open_duration)
+                {
+                    self.transition(&mut inner, BreakerState::HalfOpen, metrics);
+                    inner.probe_started = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+            BreakerState::HalfOpen => {
+                // Only one probe at a time, though don't wait forever on a
+                // probe that never reported back
+                if inner
+                    .probe_started
+                    .map_or(true, |at| at.elapsed() >= self.open_duration)
+                {
+                    inner.probe_started = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if !allowed {
+            metrics.incr_with_tags("tiles.partner.breaker.rejected", Some(&self.tags()));
+        }
+        allowed
+    }
+
+    /// Record the result of a request to the partner
+    pub fn record<T>(&self, result: &HandlerResult<T>, metrics: &Metrics) {
+        if self.threshold == 0 {
+            return;
+        }
+        match result {
+            Err(e) if e.kind().is_partner_failure() => self.record_failure(metrics),
+            // No request was sent
+            Err(e) if matches!(e.kind(), HandlerErrorKind::PartnerRateLimited(_)) => {}
+            _ => self.record_success(metrics),
+        }
+    }
+
+    fn record_success(&self, metrics: &Metrics) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == BreakerState::HalfOpen {
+            inner.failures.clear();
+            inner.probe_started = None;
+            self.transition(&mut inner, BreakerState::Closed, metrics);
+        }
+    }
+
+    fn record_failure(&self, metrics: &Metrics) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            BreakerState::Closed => {
+                inner.failures.push_back(now);
+                while inner
+                    .failures
+                    .front()
+                    .map_or(false, |at| now.duration_since(*at) > self.window)
+                {
+                    inner.failures.pop_front();
+                }
+                if inner.failures.len() >= self.threshold {
+                    inner.failures.clear();
+                    inner.opened_at = Some(now);
+                    self.transition(&mut inner, BreakerState::Open, metrics);
+                }
+            }
+            BreakerState::HalfOpen => {
+                inner.probe_started = None;
+                inner.opened_at = Some(now);
+                self.transition(&mut inner, BreakerState::Open, metrics);
+            }
+            // A request let through before the breaker opened
+            BreakerState::Open => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState, metrics: &Metrics) {
+        trace!(
+            "CircuitBreaker {}: {} -> {}",
+            &self.name,
+            inner.state,
+            state
+        );
+        inner.state = state;
+        let mut tags = self.tags();
+        tags.add_tag("state", &state.to_string());
+        metrics.incr_with_tags("tiles.partner.breaker", Some(&tags));
+    }
+
+    fn tags(&self) -> Tags {
+        let mut tags = Tags::default();
+        tags.add_tag("partner", &self.name);
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::HandlerError;
+
+    fn breaker(open_secs: u64) -> CircuitBreaker {
+        let settings = Settings {
+            partner_breaker_failures: 2,
+            partner_breaker_window_secs: 60,
+            partner_breaker_open_secs: open_secs,
+            ..Default::default()
+        };
+        CircuitBreaker::new("test", &settings)
+    }
+
+    fn failure() -> HandlerResult<()> {
+        Err(HandlerErrorKind::AdmServerError().into())
+    }
+
+    #[test]
+    fn opens_after_failures() {
+        let metrics = Metrics::noop();
+        let breaker = breaker(60);
+        assert!(breaker.allow(&metrics));
+        breaker.record(&failure(), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        // Non partner errors don't count
+        breaker.record::<()>(&Err(HandlerError::internal("oops")), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(&failure(), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow(&metrics));
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let metrics = Metrics::noop();
+        let breaker = breaker(0);
+        breaker.record(&failure(), &metrics);
+        breaker.record(&failure(), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // The open period has elapsed: allow a probe
+        assert!(breaker.allow(&metrics));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        // A failed probe reopens
+        breaker.record(&failure(), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow(&metrics));
+        breaker.record(&Ok(()), &metrics);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn disabled() {
+        let metrics = Metrics::noop();
+        let settings = Settings {
+            partner_breaker_failures: 0,
+            ..Default::default()
+        };
+        let breaker = CircuitBreaker::new("test", &settings);
+        for _ in 0..10 {
+            breaker.record(&failure(), &metrics);
+        }
+        assert!(breaker.allow(&metrics));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,346 @@
+//! Hedged partner requests
+//!
+//! When enabled (`partner_hedge`), a partner request that hasn't answered
+//! within the `partner_hedge_percentile` of its endpoint's recent latencies
+//! is "hedged": a second, identical request is sent and whichever answers
+//! successfully first wins. Latencies are tracked per endpoint URL, as a
+//! partner's endpoints (e.g. mobile and desktop) may perform differently.
+//!
+//! Hedges are globally capped to `partner_hedge_max_rate` of all partner
+//! requests via a token bucket: each request earns `partner_hedge_max_rate`
+//! of a token and each hedge spends a whole one. Hedges are also subject
+//! to the partner endpoint's rate limit (see [crate::providers::RateLimiter]):
+//! a hedge it refuses doesn't spend a token.
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::{select, Either};
+
+use crate::{metrics::Metrics, settings::Settings, tags::Tags};
+
+/// Number of recent latencies tracked per endpoint
+const LATENCY_SAMPLES: usize = 200;
+/// Don't hedge until this many latencies have been recorded for an endpoint
+const MIN_LATENCY_SAMPLES: usize = 20;
+/// Maximum number of hedge tokens that may accumulate
+const MAX_HEDGE_TOKENS: f64 = 10.0;
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Recent successful request latencies, by endpoint URL
+    latencies: HashMap<String, VecDeque<Duration>>,
+    /// The hedge budget
+    tokens: f64,
+}
+
+#[derive(Debug)]
+pub struct Hedger {
+    enabled: bool,
+    percentile: f64,
+    min_delay: Duration,
+    max_rate: f64,
+    inner: Mutex<Inner>,
+}
+
+impl Hedger {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            enabled: settings.partner_hedge,
+            percentile: settings.partner_hedge_percentile.clamp(0.0, 100.0),
+            min_delay: Duration::from_millis(settings.partner_hedge_min_delay_ms),
+            max_rate: settings.partner_hedge_max_rate.clamp(0.0, 1.0),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Send a request to a partner's `endpoint`, hedging it with a second
+    /// request (built by calling `request` again) if it's slow to answer.
+    ///
+    /// `allow_hedge` is called before sending a hedge, which is only sent if
+    /// it returns true (e.g. it could take a rate limit token).
+    pub async fn send<F, Fut, T, E, A>(
+        &self,
+        endpoint: &str,
+        request: F,
+        allow_hedge: A,
+        tags: &Tags,
+        metrics: &Metrics,
+    ) -> Result<T, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        A: FnOnce() -> bool,
+    {
+        if !self.enabled {
+            return request().await;
+        }
+        let start = Instant::now();
+        let delay = self.earn_token(endpoint);
+        let primary = Box::pin(request());
+        let delay = match delay {
+            Some(delay) => delay,
+            None => return self.record(endpoint, start, primary.await),
+        };
+
+        let primary = match select(primary, actix_rt::time::delay_for(delay)).await {
+            Either::Left((result, _)) => return self.record(endpoint, start, result),
+            Either::Right((_, primary)) => primary,
+        };
+        if !self.spend_token() {
+            metrics.incr_with_tags("tiles.partner.hedge.throttled", Some(tags));
+            return self.record(endpoint, start, primary.await);
+        }
+        if !allow_hedge() {
+            self.refund_token();
+            metrics.incr_with_tags("tiles.partner.hedge.throttled", Some(tags));
+            return self.record(endpoint, start, primary.await);
+        }
+        trace!("Hedger: hedging {} request after {:?}", endpoint, delay);
+        metrics.incr_with_tags("tiles.partner.hedge.issued", Some(tags));
+        let hedge = Box::pin(request());
+        let result = match select(primary, hedge).await {
+            // The first to fail defers to the other
+            Either::Left((Err(_), hedge)) => {
+                let result = hedge.await;
+                if result.is_ok() {
+                    metrics.incr_with_tags("tiles.partner.hedge.won", Some(tags));
+                }
+                result
+            }
+            Either::Left((result, _)) => result,
+            Either::Right((Err(_), primary)) => primary.await,
+            Either::Right((result, _)) => {
+                metrics.incr_with_tags("tiles.partner.hedge.won", Some(tags));
+                result
+            }
+        };
+        self.record(endpoint, start, result)
+    }
+
+    /// Add this request's share of the hedge budget, returning the delay
+    /// before hedging it (`None` if the endpoint's latencies aren't known yet)
+    fn earn_token(&self, endpoint: &str) -> Option<Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tokens = (inner.tokens + self.max_rate).min(MAX_HEDGE_TOKENS);
+        let latencies = inner.latencies.get(endpoint)?;
+        if latencies.len() < MIN_LATENCY_SAMPLES {
+            return None;
+        }
+        Some(percentile(latencies, self.percentile).max(self.min_delay))
+    }
+
+    fn spend_token(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.tokens >= 1.0 {
+            inner.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Return the token spent on a hedge that wasn't sent
+    fn refund_token(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tokens = (inner.tokens + 1.0).min(MAX_HEDGE_TOKENS);
+    }
+
+    /// Record the latency of a successful request
+    fn record<T, E>(&self, endpoint: &str, start: Instant, result: Result<T, E>) -> Result<T, E> {
+        if result.is_ok() {
+            let mut inner = self.inner.lock().unwrap();
+            let latencies = inner.latencies.entry(endpoint.to_owned()).or_default();
+            if latencies.len() >= LATENCY_SAMPLES {
+                latencies.pop_front();
+            }
+            latencies.push_back(start.elapsed());
+        }
+        result
+    }
+}
+
+/// The (nearest rank) `p`th percentile of a non empty list of latencies
+fn percentile(latencies: &VecDeque<Duration>, p: f64) -> Duration {
+    let mut sorted: Vec<_> = latencies.iter().copied().collect();
+    sorted.sort_unstable();
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::*;
+
+    const ENDPOINT: &str = "https://partner.example.com/tilesp/desktop";
+
+    fn hedger(max_rate: f64) -> Hedger {
+        Hedger::new(&Settings {
+            partner_hedge: true,
+            partner_hedge_percentile: 90.0,
+            partner_hedge_min_delay_ms: 10,
+            partner_hedge_max_rate: max_rate,
+            ..Default::default()
+        })
+    }
+
+    fn prime(hedger: &Hedger, latency: Duration) {
+        let mut inner = hedger.inner.lock().unwrap();
+        inner.latencies.insert(
+            ENDPOINT.to_owned(),
+            vec![latency; MIN_LATENCY_SAMPLES].into(),
+        );
+    }
+
+    #[test]
+    fn percentiles() {
+        let latencies: VecDeque<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 90.0), Duration::from_millis(90));
+        assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies, 0.0), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn budget() {
+        let hedger = hedger(0.5);
+        // Each request earns half a hedge
+        hedger.earn_token(ENDPOINT);
+        assert!(!hedger.spend_token());
+        hedger.earn_token(ENDPOINT);
+        assert!(hedger.spend_token());
+        assert!(!hedger.spend_token());
+        // Unknown latencies: no hedging yet
+        assert_eq!(hedger.earn_token(ENDPOINT), None);
+        prime(&hedger, Duration::from_millis(1));
+        assert_eq!(hedger.earn_token(ENDPOINT), Some(Duration::from_millis(10)));
+    }
+
+    #[actix_rt::test]
+    async fn hedge_wins() {
+        let hedger = hedger(1.0);
+        prime(&hedger, Duration::from_millis(20));
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Metrics::from(&StatsdClient::builder("contile", sink).build());
+        let calls = Cell::new(0);
+        let result: Result<u8, ()> = hedger
+            .send(
+                ENDPOINT,
+                || {
+                    calls.set(calls.get() + 1);
+                    let attempt = calls.get();
+                    async move {
+                        // The primary request hangs
+                        if attempt == 1 {
+                            actix_rt::time::delay_for(Duration::from_secs(5)).await;
+                        }
+                        Ok(attempt)
+                    }
+                },
+                || true,
+                &Tags::default(),
+                &metrics,
+            )
+            .await;
+        assert_eq!(result, Ok(2));
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(sent
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner.hedge.issued")));
+        assert!(sent
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner.hedge.won")));
+    }
+
+    #[actix_rt::test]
+    async fn hedge_throttled() {
+        let hedger = hedger(0.0);
+        prime(&hedger, Duration::from_millis(1));
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Metrics::from(&StatsdClient::builder("contile", sink).build());
+        let calls = Cell::new(0);
+        let result: Result<(), ()> = hedger
+            .send(
+                ENDPOINT,
+                || {
+                    calls.set(calls.get() + 1);
+                    async {
+                        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+                        Ok(())
+                    }
+                },
+                || true,
+                &Tags::default(),
+                &metrics,
+            )
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(calls.get(), 1);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(sent
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner.hedge.throttled")));
+        assert!(!sent
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner.hedge.issued")));
+    }
+
+    #[actix_rt::test]
+    async fn hedge_rate_limited() {
+        let hedger = hedger(1.0);
+        prime(&hedger, Duration::from_millis(1));
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Metrics::from(&StatsdClient::builder("contile", sink).build());
+        let calls = Cell::new(0);
+        let result: Result<(), ()> = hedger
+            .send(
+                ENDPOINT,
+                || {
+                    calls.set(calls.get() + 1);
+                    async {
+                        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+                        Ok(())
+                    }
+                },
+                // No rate limit token for the hedge
+                || false,
+                &Tags::default(),
+                &metrics,
+            )
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(calls.get(), 1);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(sent
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner.hedge.throttled")));
+        // The refused hedge didn't spend its token
+        assert!(hedger.spend_token());
+    }
+
+    #[test]
+    fn latencies_by_endpoint() {
+        let hedger = hedger(1.0);
+        prime(&hedger, Duration::from_millis(1));
+        assert!(hedger.earn_token(ENDPOINT).is_some());
+        assert_eq!(
+            hedger.earn_token("https://partner.example.com/tilesp/mobile"),
+            None
+        );
+    }
+}
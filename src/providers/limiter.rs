@@ -0,0 +1,100 @@
+//! Outbound rate limiting of partner requests
+//!
+//! A token bucket per partner endpoint, refilled at
+//! `partner_rate_limit` tokens per second up to `partner_rate_limit_burst`
+//! tokens. Each request to the endpoint (including retries and hedges)
+//! spends a token: requests that can't get one aren't sent, keeping e.g. a
+//! cold cache from swamping the partner.
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use crate::settings::Settings;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added per second (0 disables the limiter)
+    rate: f64,
+    burst: f64,
+    /// Buckets by endpoint URL
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            rate: settings.partner_rate_limit.max(0.0),
+            burst: f64::from(settings.partner_rate_limit_burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for a request to `endpoint`, returning false if none
+    /// are available
+    pub fn try_acquire(&self, endpoint: &str) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(endpoint.to_owned())
+            .or_insert_with(|| Bucket {
+                tokens: self.burst,
+                updated: now,
+            });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&Settings {
+            partner_rate_limit: rate,
+            partner_rate_limit_burst: burst,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = limiter(0.0, 1);
+        assert!((0..100).all(|_| limiter.try_acquire("https://example.com")));
+    }
+
+    #[test]
+    fn burst_per_endpoint() {
+        let limiter = limiter(0.001, 2);
+        assert!(limiter.try_acquire("https://example.com"));
+        assert!(limiter.try_acquire("https://example.com"));
+        assert!(!limiter.try_acquire("https://example.com"));
+        // Other endpoints have their own bucket
+        assert!(limiter.try_acquire("https://example.org"));
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = limiter(100.0, 1);
+        assert!(limiter.try_acquire("https://example.com"));
+        assert!(!limiter.try_acquire("https://example.com"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.try_acquire("https://example.com"));
+    }
+}
@@ -0,0 +1,381 @@
+//! Tile providers
+//!
+//! A [TileProvider] supplies the candidate tiles for a given audience. adM
+//! ([crate::adm::AdmProvider]) is currently the only implementation, but
+//! other sources (e.g. direct sold tiles or other ad networks) can be added
+//! without changing the request handler or the tiles cache.
+//!
+//! All configured providers are queried concurrently and their results
+//! merged into a single [TileResponse] according to each provider's
+//! priority and slot limits, then ordered by position ([slot_tiles]).
+//! Requests to a provider with an open [CircuitBreaker] fail fast, and slow
+//! requests may be hedged (see [Hedger]). Outbound requests to each partner
+//! endpoint are rate limited by a [RateLimiter].
+use std::{collections::HashSet, fmt::Debug, sync::Arc};
+
+use actix_http::http::header::HeaderMap;
+use actix_web_location::Location;
+use async_trait::async_trait;
+use futures::future::join_all;
+
+use crate::{
+    adm::{AdmProvider, Tile, TileResponse},
+    error::{HandlerError, HandlerErrorKind, HandlerResult},
+    metrics::Metrics,
+    server::ServerState,
+    settings::Settings,
+    tags::Tags,
+    web::DeviceInfo,
+};
+
+mod breaker;
+mod hedge;
+mod limiter;
+mod settings;
+
+pub use breaker::{BreakerState, CircuitBreaker};
+pub use hedge::Hedger;
+pub use limiter::RateLimiter;
+pub use settings::PartnerSettings;
+
+/// A source of tiles
+#[async_trait(?Send)]
+pub trait TileProvider: Debug + Send + Sync {
+    /// A short name identifying this provider in metrics and logs
+    fn name(&self) -> &str;
+
+    /// Tiles from providers with a lower priority value are placed first
+    fn priority(&self) -> u8 {
+        0
+    }
+
+    /// The maximum number of tile slots this provider may fill
+    fn max_tiles(&self) -> Option<u8> {
+        None
+    }
+
+    /// The circuit breaker guarding requests to this provider, if any
+    fn breaker(&self) -> Option<&CircuitBreaker> {
+        None
+    }
+
+    /// Fetch the candidate tiles for the audience described by `location`
+    /// and `device_info`.
+    ///
+    /// The returned tiles should already be validated and ready to be sent to
+    /// the User Agent.
+    async fn get_tiles(
+        &self,
+        state: &ServerState,
+        location: &Location,
+        device_info: &DeviceInfo,
+        tags: &mut Tags,
+        metrics: &Metrics,
+        headers: Option<&HeaderMap>,
+    ) -> HandlerResult<TileResponse>;
+}
+
+/// Build the configured [TileProvider]s from [Settings]
+pub fn providers_from_settings(settings: &Settings) -> HandlerResult<Vec<Arc<dyn TileProvider>>> {
+    let partners = PartnerSettings::list_from_settings(settings)
+        .map_err(|e| HandlerError::internal(&e.to_string()))?;
+    Ok(partners
+        .into_iter()
+        .map(|partner| Arc::new(AdmProvider::new(partner, settings)) as Arc<dyn TileProvider>)
+        .collect())
+}
+
+/// Fetch tiles for the given audience from all of the configured providers.
+///
+/// Providers are queried concurrently. Providers that fail are skipped, so
+/// long as at least one provider answered successfully. Otherwise the
+/// error of the highest priority provider is returned.
+pub async fn get_tiles(
+    state: &ServerState,
+    location: &Location,
+    device_info: &DeviceInfo,
+    tags: &mut Tags,
+    metrics: &Metrics,
+    headers: Option<&HeaderMap>,
+) -> HandlerResult<TileResponse> {
+    // Every provider records its own tags (along with its name), merged back
+    // into `tags` once they've all answered: so they're reported the same
+    // however many providers are configured
+    let mut provider_tags: Vec<Tags> = state
+        .providers
+        .iter()
+        .map(|provider| {
+            let mut provider_tags = tags.clone();
+            provider_tags.add_tag("partner", provider.name());
+            provider_tags
+        })
+        .collect();
+    let results = join_all(state.providers.iter().zip(provider_tags.iter_mut()).map(
+        |(provider, provider_tags)| async move {
+            let result = fetch(
+                provider,
+                state,
+                location,
+                device_info,
+                provider_tags,
+                metrics,
+                headers,
+            )
+            .await;
+            (provider, result)
+        },
+    ))
+    .await;
+    // The first configured provider's take precedence
+    for provider_tags in provider_tags.iter().rev() {
+        tags.extend(provider_tags.clone());
+    }
+
+    let mut responses = Vec::new();
+    let mut error: Option<(u8, HandlerError)> = None;
+    for ((provider, result), provider_tags) in results.into_iter().zip(provider_tags.iter()) {
+        match result {
+            Ok(response) => {
+                metrics.incr_with_tags("tiles.partner.success", Some(provider_tags));
+                responses.push(PartnerTiles {
+                    priority: provider.priority(),
+                    max_tiles: provider.max_tiles(),
+                    tiles: response.tiles,
+                });
+            }
+            Err(e) => {
+                warn!("providers::get_tiles: {} failed: {:?}", provider.name(), e);
+                metrics.incr_with_tags("tiles.partner.error", Some(provider_tags));
+                if error
+                    .as_ref()
+                    .map_or(true, |(priority, _)| provider.priority() < *priority)
+                {
+                    error = Some((provider.priority(), e));
+                }
+            }
+        }
+    }
+    if responses.is_empty() {
+        if let Some((_, e)) = error {
+            return Err(e);
+        }
+    }
+    let tiles = if state.providers.len() == 1 {
+        // Nothing to merge
+        responses
+            .pop()
+            .map_or_else(Vec::new, |response| response.tiles)
+    } else {
+        merge(responses)
+    };
+    Ok(TileResponse {
+        tiles: finalize(tiles, state.settings.adm_max_tiles as usize),
+    })
+}
+
+/// Slot the tiles by position and cap them at `max_tiles`
+fn finalize(tiles: Vec<Tile>, max_tiles: usize) -> Vec<Tile> {
+    let mut tiles = slot_tiles(tiles);
+    tiles.truncate(max_tiles);
+    tiles
+}
+
+/// Assign every tile a position and order them by it.
+///
+/// Tiles keep their requested position unless an earlier (higher precedence)
+/// tile already claimed it, in which case they move to the next free
+/// position. Tiles without a position fill the lowest free positions, in
+/// order.
+///
+/// Positions aren't compacted: they're the slots Firefox places the tiles
+/// in (around its own, non sponsored, tiles), so e.g. tiles requesting
+/// positions 1 and 2 keep them even when nothing claims position 0.
+pub fn slot_tiles(tiles: Vec<Tile>) -> Vec<Tile> {
+    let mut taken = HashSet::new();
+    let mut slotted = Vec::with_capacity(tiles.len());
+    let mut unpositioned = Vec::new();
+    for mut tile in tiles {
+        let requested = match tile.position {
+            Some(position) => position,
+            None => {
+                unpositioned.push(tile);
+                continue;
+            }
+        };
+        if let Some(position) = (requested..=u8::MAX).find(|p| !taken.contains(p)) {
+            if position != requested {
+                trace!(
+                    "slot_tiles: {} position {} taken, moved to {}",
+                    &tile.name,
+                    requested,
+                    position
+                );
+            }
+            taken.insert(position);
+            tile.position = Some(position);
+            slotted.push(tile);
+        }
+    }
+    let mut free = (0..=u8::MAX).filter(|p| !taken.contains(p));
+    for mut tile in unpositioned {
+        if let Some(position) = free.next() {
+            tile.position = Some(position);
+            slotted.push(tile);
+        }
+    }
+    slotted.sort_by_key(|tile| tile.position);
+    slotted
+}
+
+/// Fetch tiles from a single provider, guarded by its circuit breaker
+async fn fetch(
+    provider: &Arc<dyn TileProvider>,
+    state: &ServerState,
+    location: &Location,
+    device_info: &DeviceInfo,
+    tags: &mut Tags,
+    metrics: &Metrics,
+    headers: Option<&HeaderMap>,
+) -> HandlerResult<TileResponse> {
+    let breaker = provider.breaker();
+    if let Some(breaker) = breaker {
+        if !breaker.allow(metrics) {
+            trace!("providers::fetch: {} breaker open", provider.name());
+            return Err(HandlerErrorKind::PartnerUnavailable(provider.name().to_owned()).into());
+        }
+    }
+    let result = provider
+        .get_tiles(state, location, device_info, tags, metrics, headers)
+        .await;
+    if let Some(breaker) = breaker {
+        breaker.record(&result, metrics);
+    }
+    result
+}
+
+/// The tiles returned by a single provider along with its merge rules
+#[derive(Debug)]
+struct PartnerTiles {
+    priority: u8,
+    max_tiles: Option<u8>,
+    tiles: Vec<Tile>,
+}
+
+/// Merge the tiles from multiple providers.
+///
+/// Providers are ordered by priority (preserving their configured order for
+/// equal priorities), each filling at most `max_tiles` slots. Tiles for an
+/// advertiser already provided by a higher priority provider are dropped.
+fn merge(mut responses: Vec<PartnerTiles>) -> Vec<Tile> {
+    responses.sort_by_key(|response| response.priority);
+    let mut seen = HashSet::new();
+    let mut tiles = Vec::new();
+    for response in responses {
+        let limit = response.max_tiles.map_or(usize::MAX, usize::from);
+        let partner_tiles = response
+            .tiles
+            .into_iter()
+            .filter(|tile| seen.insert(tile.name.to_lowercase()))
+            .take(limit);
+        tiles.extend(partner_tiles);
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(name: &str) -> Tile {
+        positioned(name, None)
+    }
+
+    fn positioned(name: &str, position: Option<u8>) -> Tile {
+        Tile {
+            id: 0,
+            name: name.to_owned(),
+            url: "https://example.com/".to_owned(),
+            click_url: "https://example.com/click".to_owned(),
+            image_url: "https://example.com/image.jpg".to_owned(),
+            image_size: None,
+            impression_url: "https://example.com/impression".to_owned(),
+            position,
+        }
+    }
+
+    fn names(tiles: &[Tile]) -> Vec<&str> {
+        tiles.iter().map(|tile| tile.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_priority() {
+        let responses = vec![
+            PartnerTiles {
+                priority: 1,
+                max_tiles: None,
+                tiles: vec![tile("House"), tile("Acme")],
+            },
+            PartnerTiles {
+                priority: 0,
+                max_tiles: Some(1),
+                tiles: vec![tile("Acme"), tile("Dunder Mifflin")],
+            },
+        ];
+        let tiles = merge(responses);
+        // "Acme" is supplied by the higher priority partner (which may only
+        // fill one slot) and not repeated
+        assert_eq!(names(&tiles), vec!["Acme", "House"]);
+    }
+
+    #[test]
+    fn merge_max_tiles() {
+        let responses = vec![
+            PartnerTiles {
+                priority: 0,
+                max_tiles: None,
+                tiles: vec![tile("Acme"), tile("Dunder Mifflin")],
+            },
+            PartnerTiles {
+                priority: 0,
+                max_tiles: None,
+                tiles: vec![tile("House")],
+            },
+        ];
+        let tiles = finalize(merge(responses), 2);
+        assert_eq!(names(&tiles), vec!["Acme", "Dunder Mifflin"]);
+    }
+
+    #[test]
+    fn slot_positions() {
+        let tiles = slot_tiles(vec![
+            tile("Unpositioned"),
+            positioned("Acme", Some(2)),
+            positioned("Dunder Mifflin", Some(0)),
+            // Conflicts with Acme, which takes precedence
+            positioned("Los Pollos Hermanos", Some(2)),
+        ]);
+        assert_eq!(
+            names(&tiles),
+            vec![
+                "Dunder Mifflin",
+                "Unpositioned",
+                "Acme",
+                "Los Pollos Hermanos"
+            ]
+        );
+        let positions: Vec<_> = tiles.iter().map(|tile| tile.position).collect();
+        assert_eq!(positions, vec![Some(0), Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn slot_gaps() {
+        let tiles = slot_tiles(vec![
+            positioned("Acme", Some(2)),
+            positioned("Dunder Mifflin", Some(1)),
+        ]);
+        assert_eq!(names(&tiles), vec!["Dunder Mifflin", "Acme"]);
+        // Position 0 is left to Firefox
+        let positions: Vec<_> = tiles.iter().map(|tile| tile.position).collect();
+        assert_eq!(positions, vec![Some(1), Some(2)]);
+    }
+}
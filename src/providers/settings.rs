@@ -0,0 +1,184 @@
+use std::{collections::HashSet, convert::TryFrom};
+
+use config::ConfigError;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adm::{PseRule, QueryTemplate},
+    settings::Settings,
+};
+
+/// The settings for a single partner endpoint.
+///
+/// Partners are specified as a JSON list in `Settings::partners`, e.g.
+///
+/// ```json
+/// [
+///     {"name": "adm", "endpoint_url": "https://adm.example.com/v1",
+///      "partner_id": "demofeed", "sub1": "123456789", "priority": 0},
+///     {"name": "house", "endpoint_url": "https://house.example.com/v1",
+///      "priority": 1, "max_tiles": 1, "timeout": 2,
+///      "query": [{"name": "cc", "attribute": "country_code"}]}
+/// ]
+/// ```
+///
+/// See [QueryTemplate] for the format of `query`/`mobile_query` and
+/// [PseRule] for `rules`.
+///
+/// When no partners are specified, a single "adm" partner is built from the
+/// `adm_*` [Settings].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PartnerSettings {
+    /// Unique name of the partner (used for metrics)
+    pub name: String,
+    /// The partner's tiles API endpoint
+    pub endpoint_url: String,
+    pub partner_id: Option<String>,
+    pub sub1: Option<String>,
+    /// Mobile versions of the above (defaulting to the values above)
+    pub mobile_endpoint_url: Option<String>,
+    pub mobile_partner_id: Option<String>,
+    pub mobile_sub1: Option<String>,
+    /// The query parameters sent to the endpoint (default: adM's)
+    pub query: Option<QueryTemplate>,
+    /// The query parameters sent to the mobile endpoint (default: `query`)
+    pub mobile_query: Option<QueryTemplate>,
+    /// Ordered rules overriding the above for matching requests
+    #[serde(default)]
+    pub rules: Vec<PseRule>,
+    /// Timeout requests to this partner after this many seconds (default:
+    /// `adm_timeout`)
+    pub timeout: Option<u64>,
+    /// Tiles from partners with a lower priority value are placed first
+    /// (default: 0)
+    #[serde(default)]
+    pub priority: u8,
+    /// Maximum number of tile slots this partner may fill (default: all)
+    pub max_tiles: Option<u8>,
+}
+
+impl TryFrom<&Settings> for PartnerSettings {
+    type Error = ConfigError;
+
+    /// The default "adm" partner, built from the `adm_*` settings
+    fn try_from(settings: &Settings) -> Result<Self, Self::Error> {
+        let parse = |template: &Option<String>| {
+            template.as_deref().map(QueryTemplate::try_from).transpose()
+        };
+        Ok(Self {
+            name: "adm".to_owned(),
+            endpoint_url: settings.adm_endpoint_url.clone(),
+            partner_id: settings.adm_partner_id.clone(),
+            sub1: settings.adm_sub1.clone(),
+            mobile_endpoint_url: settings.adm_mobile_endpoint_url.clone(),
+            mobile_partner_id: settings.adm_mobile_partner_id.clone(),
+            mobile_sub1: settings.adm_mobile_sub1.clone(),
+            query: parse(&settings.adm_query_template)?,
+            mobile_query: parse(&settings.adm_mobile_query_template)?,
+            rules: match &settings.adm_partner_rules {
+                Some(rules) => PseRule::list_from_str(rules)?,
+                None => Vec::new(),
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl PartnerSettings {
+    /// Read the list of configured partners from [Settings]
+    pub fn list_from_settings(settings: &Settings) -> Result<Vec<Self>, ConfigError> {
+        let partners_str = match &settings.partners {
+            Some(partners) => partners,
+            None => return Ok(vec![Self::try_from(settings)?]),
+        };
+        let partners: Vec<Self> = serde_json::from_str(partners_str)
+            .map_err(|e| ConfigError::Message(format!("Invalid partners: {:?}", e)))?;
+        if partners.is_empty() {
+            return Err(ConfigError::Message("No partners specified".to_owned()));
+        }
+        let mut names = HashSet::new();
+        for partner in &partners {
+            if partner.name.is_empty() {
+                return Err(ConfigError::Message("Partner missing a name".to_owned()));
+            }
+            if !names.insert(partner.name.as_str()) {
+                return Err(ConfigError::Message(format!(
+                    "Duplicate partner name {:?}",
+                    partner.name
+                )));
+            }
+            if partner.endpoint_url.is_empty() {
+                return Err(ConfigError::Message(format!(
+                    "Partner {:?} missing endpoint_url",
+                    partner.name
+                )));
+            }
+            PseRule::validate(&partner.rules)?;
+        }
+        Ok(partners)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_partner() {
+        let settings = Settings {
+            adm_endpoint_url: "https://example.com/".to_owned(),
+            adm_partner_id: Some("test".to_owned()),
+            ..Default::default()
+        };
+        let partners = PartnerSettings::list_from_settings(&settings).unwrap();
+        assert_eq!(partners.len(), 1);
+        assert_eq!(partners[0].name, "adm");
+        assert_eq!(partners[0].endpoint_url, "https://example.com/");
+        assert_eq!(partners[0].partner_id, Some("test".to_owned()));
+    }
+
+    #[test]
+    fn invalid_partners() {
+        let mut settings = Settings {
+            partners: Some("[]".to_owned()),
+            ..Default::default()
+        };
+        assert!(PartnerSettings::list_from_settings(&settings).is_err());
+
+        settings.partners = Some(
+            r#"[{"name": "a", "endpoint_url": "https://example.com/"},
+                {"name": "a", "endpoint_url": "https://example.org/"}]"#
+                .to_owned(),
+        );
+        assert!(PartnerSettings::list_from_settings(&settings).is_err());
+
+        settings.partners = Some(r#"[{"name": "a", "endpoint_url": ""}]"#.to_owned());
+        assert!(PartnerSettings::list_from_settings(&settings).is_err());
+
+        settings.partners = Some(
+            r#"[{"name": "a", "endpoint_url": "https://example.com/", "priority": 1},
+                {"name": "b", "endpoint_url": "https://example.org/", "max_tiles": 1}]"#
+                .to_owned(),
+        );
+        let partners = PartnerSettings::list_from_settings(&settings).unwrap();
+        assert_eq!(partners[0].priority, 1);
+        assert_eq!(partners[1].max_tiles, Some(1));
+
+        settings.partners = Some(
+            r#"[{"name": "a", "endpoint_url": "https://example.com/",
+                 "query": [{"name": "zip", "attribute": "zip_code"}]}]"#
+                .to_owned(),
+        );
+        assert!(PartnerSettings::list_from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn invalid_adm_query_template() {
+        let settings = Settings {
+            adm_endpoint_url: "https://example.com/".to_owned(),
+            adm_query_template: Some(r#"[{"name": "zip", "attribute": "zip_code"}]"#.to_owned()),
+            ..Default::default()
+        };
+        assert!(PartnerSettings::list_from_settings(&settings).is_err());
+    }
+}
@@ -1,79 +1,631 @@
 //! Tile cache manager
 use std::{
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    sync::Arc,
-    time::{Duration, SystemTime},
+    fs,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use actix_web::{error::BlockingError, web::Bytes};
+use actix_web_location::Location;
 use cadence::StatsdClient;
 use dashmap::DashMap;
+use flate2::{write::GzEncoder, Compression};
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     adm::TileResponse,
-    error::HandlerError,
+    error::{HandlerError, HandlerResult},
     metrics::Metrics,
-    web::{FormFactor, OsFamily},
+    server::cache_backend::{Lease, TilesCacheBackend},
+    settings::Settings,
+    web::{DeviceInfo, FormFactor, OsFamily},
 };
 
+/// How often to check the shared backend while awaiting another instance
+const SHARED_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Brotli parameters for compressing tiles. They're compressed on the worker
+/// thread serving the fetch, so favor speed over the smallest output
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+
+/// Version of the [TilesCache] snapshot format: bump it on incompatible
+/// changes (to [AudienceKey] or [Tiles]) so older snapshots are ignored
+const SNAPSHOT_VERSION: u32 = 1;
+
 /// AudienceKey is the primary key used to store and fetch tiles from the
 /// local cache.
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct AudienceKey {
     /// Country in ISO 3166-1 alpha-2 format
     pub country_code: String,
     /// Region/subdivision (e.g. a US state) in ISO 3166-2 format
+    #[serde(default)]
     pub region_code: Option<String>,
     /// The DMA code (u16)
+    #[serde(default)]
     pub dma_code: Option<u16>,
     /// The form-factor (e.g. desktop, phone) of the device
     pub form_factor: FormFactor,
     /// Platform OS
     pub os_family: OsFamily,
     /// Only serve legacy
+    #[serde(default)]
     pub legacy_only: bool,
 }
 
+impl AudienceKey {
+    pub fn new(location: &Location, device_info: &DeviceInfo) -> Self {
+        Self {
+            country_code: location.country(),
+            region_code: if location.region() != "" {
+                Some(location.region())
+            } else {
+                None
+            },
+            dma_code: location.dma,
+            form_factor: device_info.form_factor,
+            os_family: device_info.os_family,
+            legacy_only: device_info.legacy_only(),
+        }
+    }
+
+    /// A location within this audience (e.g. for prefetching its tiles)
+    pub fn location(&self) -> Result<Location, HandlerError> {
+        let mut builder = Location::build().country(self.country_code.clone());
+        if let Some(region_code) = &self.region_code {
+            builder = builder.region(region_code.clone());
+        }
+        if let Some(dma_code) = self.dma_code {
+            builder = builder.dma(dma_code);
+        }
+        builder
+            .finish()
+            .map_err(|e| HandlerError::internal(&format!("Invalid audience location: {:?}", e)))
+    }
+
+    /// A device within this audience: running the oldest Firefox version
+    /// of its legacy (or non legacy) group
+    pub fn device_info(&self) -> DeviceInfo {
+        let ff_version = match (self.os_family, self.legacy_only) {
+            (OsFamily::IOs, true) => 35,
+            (OsFamily::IOs, false) => 36,
+            (_, true) => 90,
+            (_, false) => 91,
+        };
+        DeviceInfo {
+            form_factor: self.form_factor,
+            os_family: self.os_family,
+            ff_version,
+        }
+    }
+}
+
+/// The optional dimensions of an [AudienceKey]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyDimension {
+    Region,
+    Dma,
+}
+
+/// Which optional dimensions take part in each country's [AudienceKey]
+///
+/// Configured via `tiles_cache_key_dimensions`: a JSON map of countries to
+/// their dimensions, `"*"` applying to unlisted countries, e.g. `{"US":
+/// ["region", "dma"], "CA": ["region"], "*": []}`. Countries without an
+/// entry (nor a `"*"` one) use every dimension.
+///
+/// Dimensions left out of the key are also left out of the partner request
+/// (and the filtering of its tiles), so the cached tiles stay the same for
+/// every request of the audience. The exception are DMAs ignored by an
+/// advertiser (`ignore_dmas`): they're always kept, so their tiles are still
+/// filtered out.
+#[derive(Clone, Debug, Default)]
+pub struct KeyDimensions {
+    by_country: HashMap<String, HashSet<KeyDimension>>,
+}
+
+impl KeyDimensions {
+    pub fn from_settings(settings: &Settings) -> Result<Self, HandlerError> {
+        let by_country = match &settings.tiles_cache_key_dimensions {
+            Some(dimensions) => serde_json::from_str(dimensions).map_err(|e| {
+                HandlerError::internal(&format!("Invalid tiles_cache_key_dimensions: {:?}", e))
+            })?,
+            None => HashMap::new(),
+        };
+        Ok(Self { by_country })
+    }
+
+    fn includes(&self, country_code: &str, dimension: KeyDimension) -> bool {
+        self.by_country
+            .get(country_code)
+            .or_else(|| self.by_country.get("*"))
+            .map_or(true, |dimensions| dimensions.contains(&dimension))
+    }
+
+    /// Drop the dimensions not taking part in the key from a location
+    /// (keeping the DMAs for which `ignored_dma` is true)
+    pub fn location<F>(&self, mut location: Location, ignored_dma: F) -> Location
+    where
+        F: Fn(u16) -> bool,
+    {
+        let country_code = location.country();
+        if !self.includes(&country_code, KeyDimension::Region) {
+            location.region = None;
+        }
+        if !self.includes(&country_code, KeyDimension::Dma)
+            && !location.dma.map_or(false, ignored_dma)
+        {
+            location.dma = None;
+        }
+        location
+    }
+
+    /// Drop the dimensions not taking part in the key from an audience
+    /// (keeping the DMAs for which `ignored_dma` is true)
+    pub fn audience_key<F>(&self, mut audience_key: AudienceKey, ignored_dma: F) -> AudienceKey
+    where
+        F: Fn(u16) -> bool,
+    {
+        if !self.includes(&audience_key.country_code, KeyDimension::Region) {
+            audience_key.region_code = None;
+        }
+        if !self.includes(&audience_key.country_code, KeyDimension::Dma)
+            && !audience_key.dma_code.map_or(false, ignored_dma)
+        {
+            audience_key.dma_code = None;
+        }
+        audience_key
+    }
+}
+
+/// The result of checking the shared cache backend for an audience's tiles
+#[derive(Debug)]
+pub enum SharedLookup {
+    /// Unexpired tiles fetched by another instance
+    Hit(Tiles),
+    /// Fetch the tiles from the partner, holding the lease to do so (if
+    /// there's a shared backend)
+    Fetch(Option<Lease>),
+    /// Another instance is fetching the tiles
+    Busy,
+}
+
 #[derive(Debug, Clone)]
 pub struct TilesCache {
     inner: Arc<DashMap<AudienceKey, TilesState>>,
+    /// Tiles shared with other instances (see [crate::server::cache_backend])
+    shared: Option<Arc<dyn TilesCacheBackend>>,
+    /// When each (lowercased) advertiser's tiles were last invalidated:
+    /// tiles including the advertiser fetched (or writes prepared) before
+    /// then aren't cached
+    invalidated: Arc<DashMap<String, Invalidation>>,
+    /// Incremented by every invalidation, ordering them against writes
+    generation: Arc<AtomicU64>,
+}
+
+/// When an advertiser's tiles were invalidated
+#[derive(Clone, Copy, Debug)]
+struct Invalidation {
+    /// The [TilesCache] generation: comparable to this instance's writes
+    generation: u64,
+    /// Comparable (clocks permitting) to other instances' tiles
+    time: SystemTime,
 }
 
 impl TilesCache {
     pub fn new(capacity: usize) -> Self {
         Self {
             inner: Arc::new(DashMap::with_capacity(capacity)),
+            shared: None,
+            invalidated: Default::default(),
+            generation: Default::default(),
+        }
+    }
+
+    /// Share tiles with other instances via `backend`
+    pub fn with_backend(self, backend: Option<Arc<dyn TilesCacheBackend>>) -> Self {
+        Self {
+            shared: backend,
+            ..self
+        }
+    }
+
+    /// Check the shared backend for tiles fetched by another instance (that
+    /// don't expire within `ahead`, nor predate an invalidation of their
+    /// advertisers). If there are none, attempt to acquire the lease to
+    /// fetch them ourselves.
+    ///
+    /// A failing backend is skipped (the tiles are fetched without a lease).
+    pub async fn shared_lookup(
+        &self,
+        audience_key: &AudienceKey,
+        max_age: Duration,
+        ahead: Duration,
+        lease_ttl: Duration,
+        metrics: &Metrics,
+    ) -> SharedLookup {
+        let shared = match &self.shared {
+            Some(shared) => shared,
+            None => return SharedLookup::Fetch(None),
+        };
+        match shared.get(audience_key).await {
+            Ok(Some(tiles))
+                if !tiles.expires_within(ahead)
+                    && !tiles.too_old(max_age)
+                    && !self.invalidated_after_fetch(&tiles) =>
+            {
+                return SharedLookup::Hit(tiles)
+            }
+            Ok(_) => {}
+            Err(e) => {
+                shared_error(&e, metrics);
+                return SharedLookup::Fetch(None);
+            }
+        }
+        match shared.try_lease(audience_key, lease_ttl).await {
+            Ok(Some(lease)) => SharedLookup::Fetch(Some(lease)),
+            Ok(None) => SharedLookup::Busy,
+            Err(e) => {
+                shared_error(&e, metrics);
+                SharedLookup::Fetch(None)
+            }
         }
     }
 
-    pub fn spawn_periodic_reporter(&self, interval: Duration, metrics: StatsdClient) {
+    /// Wait (up to `budget`) for another instance to share an audience's
+    /// tiles
+    pub async fn shared_await(
+        &self,
+        audience_key: &AudienceKey,
+        max_age: Duration,
+        budget: Duration,
+        metrics: &Metrics,
+    ) -> Option<Tiles> {
+        let shared = self.shared.as_ref()?;
+        let deadline = Instant::now() + budget;
+        while Instant::now() < deadline {
+            actix_rt::time::delay_for(SHARED_POLL_INTERVAL.min(budget)).await;
+            match shared.get(audience_key).await {
+                Ok(Some(tiles))
+                    if !tiles.expired()
+                        && !tiles.too_old(max_age)
+                        && !self.invalidated_after_fetch(&tiles) =>
+                {
+                    return Some(tiles)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    shared_error(&e, metrics);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    /// Share the tiles fetched for `handle`'s write with other instances
+    /// (unless they include advertisers invalidated since it was prepared)
+    pub async fn shared_set<F>(
+        &self,
+        handle: &WriteHandle<'_, F>,
+        tiles: &Tiles,
+        max_age: Duration,
+        metrics: &Metrics,
+    ) where
+        F: FnOnce(()),
+    {
+        if self.invalidated_since(tiles, handle.prepared) {
+            return;
+        }
+        if let Some(shared) = &self.shared {
+            if let Err(e) = shared.set(handle.audience_key, tiles, max_age).await {
+                shared_error(&e, metrics);
+            }
+        }
+    }
+
+    /// Remove audiences' tiles from the shared backend
+    pub async fn shared_delete(&self, audience_keys: &[AudienceKey], metrics: &Metrics) {
+        if let Some(shared) = &self.shared {
+            for audience_key in audience_keys {
+                if let Err(e) = shared.delete(audience_key).await {
+                    shared_error(&e, metrics);
+                }
+            }
+        }
+    }
+
+    /// Release the lease acquired by [TilesCache::shared_lookup]
+    pub async fn shared_release(&self, lease: Option<Lease>, metrics: &Metrics) {
+        if let (Some(shared), Some(lease)) = (&self.shared, lease) {
+            if let Err(e) = shared.release(&lease).await {
+                shared_error(&e, metrics);
+            }
+        }
+    }
+
+    pub fn spawn_periodic_reporter(
+        &self,
+        interval: Duration,
+        limits: CacheLimits,
+        metrics: StatsdClient,
+    ) {
         let cache = self.clone();
         let metrics = Metrics::from(&metrics);
         actix_rt::spawn(async move {
             loop {
-                tiles_cache_garbage_collect(&cache, &metrics).await;
+                tiles_cache_garbage_collect(&cache, &limits, &metrics).await;
                 actix_rt::time::delay_for(interval).await;
             }
         });
     }
 
-    /// Get an immutable reference to an entry in the cache
-    pub fn get(
+    /// Write the cache's tiles to a snapshot at `path` (e.g. on shutdown),
+    /// returning the number of entries written
+    pub fn write_snapshot(&self, path: &Path) -> HandlerResult<usize> {
+        let entries: Vec<_> = self
+            .inner
+            .iter()
+            .filter_map(|refm| match refm.value() {
+                TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } => {
+                    Some(SnapshotEntry {
+                        audience_key: refm.key().clone(),
+                        tiles: tiles.clone(),
+                    })
+                }
+                TilesState::Populating { .. } => None,
+            })
+            .collect();
+        let count = entries.len();
+        let snapshot = serde_json::to_vec(&Snapshot {
+            version: SNAPSHOT_VERSION,
+            entries,
+        })
+        .map_err(|e| HandlerError::internal(&e.to_string()))?;
+        // Write atomically, so a crash mid write doesn't leave a truncated
+        // snapshot
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, snapshot).map_err(|e| HandlerError::internal(&e.to_string()))?;
+        fs::rename(&tmp, path).map_err(|e| HandlerError::internal(&e.to_string()))?;
+        Ok(count)
+    }
+
+    /// Reload the still valid tiles from a snapshot written by
+    /// [TilesCache::write_snapshot], giving up after `timeout`. Returns the
+    /// number of entries loaded.
+    ///
+    /// Snapshots of another format version are rejected.
+    pub async fn load_snapshot(
+        &self,
+        path: &Path,
+        max_age: Duration,
+        timeout: Duration,
+    ) -> HandlerResult<usize> {
+        let path = path.to_owned();
+        let read = actix_web::web::block(move || read_snapshot(&path));
+        let entries = match actix_rt::time::timeout(timeout, read).await {
+            Ok(Ok(entries)) => entries,
+            Ok(Err(BlockingError::Error(e))) => return Err(HandlerError::internal(&e)),
+            Ok(Err(BlockingError::Canceled)) => {
+                return Err(HandlerError::internal("Snapshot read canceled"))
+            }
+            Err(_) => {
+                return Err(HandlerError::internal(&format!(
+                    "Snapshot read timed out after {:?}",
+                    timeout
+                )))
+            }
+        };
+        let mut count = 0;
+        for SnapshotEntry {
+            audience_key,
+            tiles,
+        } in entries
+        {
+            if tiles.expired() || tiles.too_old(max_age) {
+                continue;
+            }
+            // Don't clobber entries populated in the meantime
+            if let dashmap::mapref::entry::Entry::Vacant(entry) = self.inner.entry(audience_key) {
+                entry.insert(TilesState::Fresh { tiles });
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// The audiences accessed within `accessed_within` whose tiles expire
+    /// within `ahead`, the most recently accessed first
+    pub fn refresh_candidates(
+        &self,
+        ahead: Duration,
+        accessed_within: Duration,
+    ) -> Vec<AudienceKey> {
+        let accessed_since = now_millis().saturating_sub(accessed_within.as_millis() as u64);
+        let mut candidates: Vec<_> = self
+            .inner
+            .iter()
+            .filter_map(|refm| match refm.value() {
+                TilesState::Fresh { tiles }
+                    if tiles.accessed() >= accessed_since && tiles.expires_within(ahead) =>
+                {
+                    Some((tiles.accessed(), refm.key().clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        candidates.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+        candidates
+            .into_iter()
+            .map(|(_, audience_key)| audience_key)
+            .collect()
+    }
+
+    /// Describe every entry in the cache
+    pub fn entries(&self) -> Vec<EntryInfo> {
+        self.inner
+            .iter()
+            .map(|refm| EntryInfo::new(refm.key(), refm.value()))
+            .collect()
+    }
+
+    /// Remove the entries with tiles matching `predicate` (entries being
+    /// populated are left alone), returning their audiences.
+    ///
+    /// Only this instance's entries are matched: remove them from the shared
+    /// backend via [TilesCache::shared_delete]
+    pub fn purge<F>(&self, predicate: F) -> Vec<AudienceKey>
+    where
+        F: Fn(&AudienceKey, &Tiles) -> bool,
+    {
+        let mut purged = Vec::new();
+        self.inner
+            .retain(|audience_key, tiles_state| match tiles_state {
+                TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } => {
+                    if predicate(audience_key, tiles) {
+                        purged.push(audience_key.clone());
+                        return false;
+                    }
+                    true
+                }
+                TilesState::Populating { .. } => true,
+            });
+        purged
+    }
+
+    /// Remove the entries with tiles including any of the (lowercased)
+    /// `advertisers`, returning their audiences (to remove from the shared
+    /// backend via [TilesCache::shared_delete]).
+    ///
+    /// The results of writes in flight including the advertisers are
+    /// dropped, as are other instances' tiles including them fetched before
+    /// now
+    pub fn invalidate_advertisers(&self, advertisers: &HashSet<String>) -> Vec<AudienceKey> {
+        if advertisers.is_empty() {
+            return Vec::new();
+        }
+        let invalidation = Invalidation {
+            generation: self.generation.fetch_add(1, Ordering::SeqCst) + 1,
+            time: SystemTime::now(),
+        };
+        for advertiser in advertisers {
+            self.invalidated.insert(advertiser.clone(), invalidation);
+        }
+        // Purged after recording the invalidation: so writes completing
+        // meanwhile are either dropped or purged
+        self.purge(|_, tiles| !tiles.advertisers().is_disjoint(advertisers))
+    }
+
+    /// Whether advertisers included in `tiles` were invalidated after the
+    /// `generation` (of a write)
+    fn invalidated_since(&self, tiles: &Tiles, generation: u64) -> bool {
+        self.any_invalidated(tiles, |invalidation| invalidation.generation > generation)
+    }
+
+    /// Whether advertisers included in (another instance's) `tiles` were
+    /// invalidated after they were fetched
+    fn invalidated_after_fetch(&self, tiles: &Tiles) -> bool {
+        self.any_invalidated(tiles, |invalidation| invalidation.time > tiles.fetched)
+    }
+
+    fn any_invalidated<F>(&self, tiles: &Tiles, predicate: F) -> bool
+    where
+        F: Fn(&Invalidation) -> bool,
+    {
+        // Skip deserializing the tiles when nothing was ever invalidated
+        if self.invalidated.is_empty() {
+            return false;
+        }
+        tiles.advertisers().iter().any(|advertiser| {
+            self.invalidated
+                .get(advertiser)
+                .map_or(false, |invalidation| predicate(&invalidation))
+        })
+    }
+
+    /// Age all the tiles in the cache by `by`, as if they were fetched that
+    /// much earlier
+    #[cfg(test)]
+    pub fn backdate(&self, by: Duration) {
+        for mut refm in self.inner.iter_mut() {
+            if let TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } = refm.value_mut()
+            {
+                tiles.expiry -= by;
+                tiles.fetched -= by;
+            }
+        }
+    }
+
+    /// The audiences with tiles in the cache, the most recently accessed
+    /// first
+    pub fn audience_keys(&self) -> Vec<AudienceKey> {
+        let mut audiences: Vec<_> = self
+            .inner
+            .iter()
+            .filter_map(|refm| match refm.value() {
+                TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } => {
+                    Some((tiles.accessed(), refm.key().clone()))
+                }
+                TilesState::Populating { .. } => None,
+            })
+            .collect();
+        audiences.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+        audiences
+            .into_iter()
+            .map(|(_, audience_key)| audience_key)
+            .collect()
+    }
+
+    /// Get an immutable reference to an entry in the cache, without marking
+    /// it as recently accessed
+    pub fn peek(
         &self,
         audience_key: &AudienceKey,
     ) -> Option<dashmap::mapref::one::Ref<'_, AudienceKey, TilesState>> {
         self.inner.get(audience_key)
     }
 
+    /// Get an immutable reference to an entry in the cache (marking it as
+    /// recently accessed)
+    pub fn get(
+        &self,
+        audience_key: &AudienceKey,
+    ) -> Option<dashmap::mapref::one::Ref<'_, AudienceKey, TilesState>> {
+        let refm = self.inner.get(audience_key)?;
+        if let TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } = refm.value() {
+            tiles.touch();
+        }
+        Some(refm)
+    }
+
     /// Prepare to write to the cache.
     ///
     /// Sets the cache entry to the Refreshing/Populating states.
     /// `WriteHandle` resets those states when it goes out of scope if no
     /// `insert` call was issued (due to errors or panics).
+    ///
+    /// Other requests may await the result of a Populating write via its
+    /// [Inflight] future.
     pub fn prepare_write<'a>(
         &'a self,
         audience_key: &'a AudienceKey,
         expired: bool,
     ) -> WriteHandle<'a, impl FnOnce(()) + '_> {
+        let mut sender = None;
         if expired {
             // The cache entry's expired and we're about to refresh it
             trace!("prepare_write: Fresh now expired, Refreshing");
@@ -87,10 +639,50 @@ impl TilesCache {
         } else {
             // We'll populate this cache entry for probably the first time
             trace!("prepare_write: Populating");
-            self.inner
-                .insert(audience_key.clone(), TilesState::Populating);
+            let (tx, rx) = oneshot::channel();
+            sender = Some(tx);
+            self.inner.insert(
+                audience_key.clone(),
+                TilesState::Populating {
+                    inflight: rx.shared(),
+                },
+            );
         };
 
+        self.write_handle(audience_key, expired, sender)
+    }
+
+    /// Prepare to refresh an entry ahead of its expiry.
+    ///
+    /// As [TilesCache::prepare_write] for an expired entry, but `None` unless
+    /// the entry's currently Fresh (e.g. another task's already refreshing
+    /// it).
+    pub fn prepare_refresh<'a>(
+        &'a self,
+        audience_key: &'a AudienceKey,
+    ) -> Option<WriteHandle<'a, impl FnOnce(()) + '_>> {
+        let mut refreshing = false;
+        self.inner
+            .alter(audience_key, |_, tiles_state| match tiles_state {
+                TilesState::Fresh { tiles } => {
+                    refreshing = true;
+                    TilesState::Refreshing { tiles }
+                }
+                _ => tiles_state,
+            });
+        if !refreshing {
+            return None;
+        }
+        trace!("prepare_refresh: Fresh, Refreshing ahead of expiry");
+        Some(self.write_handle(audience_key, true, None))
+    }
+
+    fn write_handle<'a>(
+        &'a self,
+        audience_key: &'a AudienceKey,
+        expired: bool,
+        sender: Option<oneshot::Sender<Tiles>>,
+    ) -> WriteHandle<'a, impl FnOnce(()) + '_> {
         let guard = scopeguard::guard((), move |_| {
             trace!("prepare_write (ScopeGuard cleanup): Resetting state");
             if expired {
@@ -104,14 +696,16 @@ impl TilesCache {
             } else {
                 // Clear the entry: a later request will retry populating again
                 self.inner.remove_if(audience_key, |_, tiles_state| {
-                    matches!(tiles_state, TilesState::Populating)
+                    matches!(tiles_state, TilesState::Populating { .. })
                 });
             }
         });
         WriteHandle {
             cache: self,
             audience_key,
+            prepared: self.generation.load(Ordering::SeqCst),
             guard,
+            sender,
         }
     }
 }
@@ -120,22 +714,50 @@ impl TilesCache {
 ///
 /// This will reset the temporary state set by `prepare_write` when it's gone
 /// out of scope and no `insert` was issued (e.g. in the case of errors or
-/// panics).
+/// panics). Any requests awaiting the entry's [Inflight] future are then
+/// cancelled.
 pub struct WriteHandle<'a, F>
 where
     F: FnOnce(()),
 {
     cache: &'a TilesCache,
     audience_key: &'a AudienceKey,
+    /// The cache's generation when the write was prepared: it's dropped if
+    /// advertisers its tiles include are invalidated meanwhile
+    prepared: u64,
     guard: scopeguard::ScopeGuard<(), F>,
+    /// Notifies requests awaiting a Populating entry
+    sender: Option<oneshot::Sender<Tiles>>,
 }
 
 impl<F> WriteHandle<'_, F>
 where
     F: FnOnce(()),
 {
-    /// Insert a value into the cache for our audience_key
-    pub fn insert(self, tiles: TilesState) {
+    /// Insert a value into the cache for our audience_key (unless it includes
+    /// advertisers invalidated while it was in flight: then the entry's
+    /// reset, as when no write occurs)
+    pub fn insert(self, mut tiles: TilesState) {
+        if let TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } = &tiles {
+            if self.cache.invalidated_since(tiles, self.prepared) {
+                trace!("WriteHandle: invalidated while in flight, dropping the write");
+                return;
+            }
+        }
+        if let TilesState::Fresh { tiles: new } = &mut tiles {
+            // Refreshing the tiles doesn't count as an access (or idle tiles
+            // would be refreshed ahead of their expiry forever)
+            if let Some(refm) = self.cache.inner.get(self.audience_key) {
+                if let TilesState::Refreshing { tiles: old } = refm.value() {
+                    new.accessed.store(old.accessed(), Ordering::Relaxed);
+                }
+            }
+        }
+        if let (Some(sender), TilesState::Fresh { tiles }) = (self.sender, &tiles) {
+            // Share the result with any requests awaiting it (they may
+            // have given up waiting already)
+            let _ = sender.send(tiles.clone());
+        }
         self.cache.inner.insert(self.audience_key.clone(), tiles);
         // With the write completed cancel scopeguard's cleanup
         scopeguard::ScopeGuard::into_inner(self.guard);
@@ -143,20 +765,84 @@ where
     }
 }
 
+fn shared_error(e: &HandlerError, metrics: &Metrics) {
+    warn!("Shared tiles cache error: {:?}", e);
+    metrics.incr("tiles_cache.shared.error");
+}
+
+/// A summary of a cache entry
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    pub audience_key: AudienceKey,
+    /// One of "populating", "fresh" or "refreshing"
+    pub state: &'static str,
+    /// Seconds since the tiles were fetched
+    pub age: Option<u64>,
+    /// Seconds until the tiles expire (0 once they have)
+    pub expires_in: Option<u64>,
+    /// Bytes of tiles (in all their encodings)
+    pub size: usize,
+}
+
+impl EntryInfo {
+    fn new(audience_key: &AudienceKey, tiles_state: &TilesState) -> Self {
+        let tiles = tiles_state.tiles();
+        Self {
+            audience_key: audience_key.clone(),
+            state: tiles_state.name(),
+            age: tiles.map(|tiles| tiles.age().as_secs()),
+            expires_in: tiles.map(|tiles| tiles.remaining().as_secs()),
+            size: tiles_state.size(),
+        }
+    }
+}
+
+/// The shared result of a task populating a cache entry. Resolves to
+/// `Err(Canceled)` if the task failed to populate the entry.
+pub type Inflight = Shared<oneshot::Receiver<Tiles>>;
+
 #[derive(Clone, Debug)]
 /// Wrapper around Tiles with additional state about any outstanding partner
 /// requests
 pub enum TilesState {
-    /// A task is currently populating this entry (via [crate::adm::get_tiles])
-    Populating,
+    /// A task is currently populating this entry (via
+    /// [crate::providers::get_tiles])
+    Populating { inflight: Inflight },
     /// Tiles that haven't expired (or been identified as expired) yet
     Fresh { tiles: Tiles },
     /// A task is currently refreshing this expired entry (via
-    /// [crate::adm::get_tiles])
+    /// [crate::providers::get_tiles])
     Refreshing { tiles: Tiles },
 }
 
 impl TilesState {
+    /// The name of the state
+    pub fn name(&self) -> &'static str {
+        match self {
+            TilesState::Populating { .. } => "populating",
+            TilesState::Fresh { .. } => "fresh",
+            TilesState::Refreshing { .. } => "refreshing",
+        }
+    }
+
+    /// The entry's tiles (`None` while Populating)
+    pub fn tiles(&self) -> Option<&Tiles> {
+        match self {
+            TilesState::Populating { .. } => None,
+            TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } => Some(tiles),
+        }
+    }
+
+    /// Whether this entry's tiles are past the hard maximum age
+    fn too_old(&self, max_age: Duration) -> bool {
+        match self {
+            TilesState::Populating { .. } => false,
+            TilesState::Fresh { tiles } | TilesState::Refreshing { tiles } => {
+                tiles.too_old(max_age)
+            }
+        }
+    }
+
     fn size(&self) -> usize {
         match self {
             TilesState::Populating { .. } => 0,
@@ -165,10 +851,19 @@ impl TilesState {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Tiles {
     pub content: TilesContent,
     expiry: SystemTime,
+    /// When the tiles were fetched from the partner
+    fetched: SystemTime,
+    /// Strong validator of the `content` (`None` when empty)
+    #[serde(default)]
+    etag: Option<String>,
+    /// When the tiles were last read from the cache (in milliseconds since
+    /// the UNIX epoch), for LRU eviction. Local to this instance
+    #[serde(skip, default = "accessed_now")]
+    accessed: Arc<AtomicU64>,
 }
 
 impl Tiles {
@@ -180,48 +875,898 @@ impl Tiles {
         let json = serde_json::to_string(&tile_response)
             .map_err(|e| HandlerError::internal(&format!("Response failed to serialize: {}", e)))?;
         Ok(Self {
-            content: TilesContent::Json(json),
+            etag: Some(format!(
+                "\"{}\"",
+                &blake3::hash(json.as_bytes()).to_hex()[..32]
+            )),
+            content: TilesContent::Json(json.into()),
             ..empty
         })
     }
 
     pub fn empty(ttl: u32) -> Self {
+        let fetched = SystemTime::now();
         Self {
             content: TilesContent::Empty,
-            expiry: SystemTime::now() + Duration::from_secs(ttl as u64),
+            expiry: fetched + Duration::from_secs(ttl as u64),
+            fetched,
+            etag: None,
+            accessed: accessed_now(),
         }
     }
 
     pub fn expired(&self) -> bool {
         self.expiry <= SystemTime::now()
     }
+
+    /// Whether these (expired) tiles may still be served when refreshing
+    /// them fails: until `stale_if_error` past their expiry
+    pub fn stale_if_error(&self, stale_if_error: Duration) -> bool {
+        self.expiry + stale_if_error > SystemTime::now()
+    }
+
+    /// Whether these tiles are older than the hard maximum age and must no
+    /// longer be served
+    pub fn too_old(&self, max_age: Duration) -> bool {
+        self.fetched + max_age <= SystemTime::now()
+    }
+
+    /// The time remaining until these tiles expire (zero once they have)
+    pub fn remaining(&self) -> Duration {
+        self.expiry
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// The time since these tiles were fetched from the partner
+    pub fn age(&self) -> Duration {
+        self.fetched.elapsed().unwrap_or_default()
+    }
+
+    /// Whether these tiles include one of the advertiser's (by case
+    /// insensitive name)
+    pub fn has_advertiser(&self, name: &str) -> bool {
+        self.advertisers().contains(&name.to_lowercase())
+    }
+
+    /// The (lowercased) names of the advertisers included in these tiles
+    pub fn advertisers(&self) -> HashSet<String> {
+        let payload = match &self.content {
+            TilesContent::Json(payload) => payload,
+            TilesContent::Empty => return HashSet::new(),
+        };
+        match serde_json::from_str::<TileResponse>(payload.json()) {
+            Ok(response) => response
+                .tiles
+                .iter()
+                .map(|tile| tile.name.to_lowercase())
+                .collect(),
+            Err(e) => {
+                warn!("Cached tiles failed to deserialize: {:?}", e);
+                HashSet::new()
+            }
+        }
+    }
+
+    /// The `ETag` of these tiles' content
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    /// Whether these tiles expire within `ahead` (or already have)
+    pub fn expires_within(&self, ahead: Duration) -> bool {
+        self.expiry <= SystemTime::now() + ahead
+    }
+
+    /// Whether these tiles expired more than `grace` ago
+    pub fn expired_since(&self, grace: Duration) -> bool {
+        self.expiry + grace <= SystemTime::now()
+    }
+
+    /// Mark these tiles as recently accessed
+    pub fn touch(&self) {
+        self.accessed.store(now_millis(), Ordering::Relaxed);
+    }
+
+    /// When these tiles were last accessed (in milliseconds since the UNIX
+    /// epoch)
+    pub fn accessed(&self) -> u64 {
+        self.accessed.load(Ordering::Relaxed)
+    }
 }
 
-#[derive(Clone, Debug)]
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn accessed_now() -> Arc<AtomicU64> {
+    Arc::new(AtomicU64::new(now_millis()))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum TilesContent {
-    Json(String),
+    Json(JsonPayload),
     Empty,
 }
 
 impl TilesContent {
+    /// The size of the content, including all its encodings
     fn size(&self) -> usize {
         match self {
-            Self::Json(json) => json.len(),
+            Self::Json(payload) => payload.size(),
             _ => 0,
         }
     }
 }
 
-async fn tiles_cache_garbage_collect(cache: &TilesCache, metrics: &Metrics) {
+/// A content coding of a [JsonPayload]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token (`None` for identity)
+    pub fn token(self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gzip"),
+            Self::Identity => None,
+        }
+    }
+}
+
+/// Serialized tiles along with their precompressed variants, compressed
+/// once when built. Stored (in a shared backend or snapshot) with the
+/// variants, so reading them back doesn't compress them again
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "StoredPayload", into = "StoredPayload")]
+pub struct JsonPayload {
+    json: Bytes,
+    gzip: Option<Bytes>,
+    brotli: Option<Bytes>,
+}
+
+impl JsonPayload {
+    pub fn json(&self) -> &str {
+        // Always built from a String
+        std::str::from_utf8(&self.json).unwrap_or_default()
+    }
+
+    /// The JSON, as sent without a content coding
+    pub fn bytes(&self) -> &Bytes {
+        &self.json
+    }
+
+    /// The payload in the `encoding` (`None` if it failed to compress)
+    pub fn encoded(&self, encoding: Encoding) -> Option<&Bytes> {
+        match encoding {
+            Encoding::Brotli => self.brotli.as_ref(),
+            Encoding::Gzip => self.gzip.as_ref(),
+            Encoding::Identity => Some(self.bytes()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.json.len()
+            + self.gzip.as_ref().map_or(0, Bytes::len)
+            + self.brotli.as_ref().map_or(0, Bytes::len)
+    }
+}
+
+impl From<String> for JsonPayload {
+    fn from(json: String) -> Self {
+        let gzip = {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(json.as_bytes())
+                .and_then(|_| encoder.finish())
+        };
+        let brotli = {
+            let mut encoder = brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_LG_WINDOW_SIZE,
+            );
+            // Finished by into_inner
+            encoder
+                .write_all(json.as_bytes())
+                .map(|_| encoder.into_inner())
+        };
+        if let Err(e) = gzip.as_ref().and(brotli.as_ref()) {
+            warn!("Tiles failed to compress: {:?}", e);
+        }
+        Self {
+            json: json.into(),
+            gzip: gzip.ok().map(Bytes::from),
+            brotli: brotli.ok().map(Bytes::from),
+        }
+    }
+}
+
+/// The stored form of a [JsonPayload]: its compressed variants are base64
+/// encoded. Tiles stored as only the JSON (by older versions) are
+/// compressed when read back
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredPayload {
+    Encoded {
+        json: String,
+        gzip: Option<String>,
+        brotli: Option<String>,
+    },
+    Plain(String),
+}
+
+impl TryFrom<StoredPayload> for JsonPayload {
+    type Error = base64::DecodeError;
+
+    fn try_from(stored: StoredPayload) -> Result<Self, Self::Error> {
+        let (json, gzip, brotli) = match stored {
+            StoredPayload::Encoded { json, gzip, brotli } => (json, gzip, brotli),
+            StoredPayload::Plain(json) => return Ok(json.into()),
+        };
+        let decode = |variant: Option<String>| {
+            variant
+                .map(|variant| base64::decode(variant).map(Bytes::from))
+                .transpose()
+        };
+        Ok(Self {
+            json: json.into(),
+            gzip: decode(gzip)?,
+            brotli: decode(brotli)?,
+        })
+    }
+}
+
+impl From<JsonPayload> for StoredPayload {
+    fn from(payload: JsonPayload) -> Self {
+        let encode = |variant: Option<Bytes>| variant.map(base64::encode);
+        Self::Encoded {
+            json: payload.json().to_owned(),
+            gzip: encode(payload.gzip),
+            brotli: encode(payload.brotli),
+        }
+    }
+}
+
+/// A [TilesCache] persisted across restarts
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    version: u32,
+    entries: Vec<SnapshotEntry>,
+}
+
+/// Read ahead of the [Snapshot]'s entries, to reject other versions
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SnapshotEntry {
+    audience_key: AudienceKey,
+    tiles: Tiles,
+}
+
+fn read_snapshot(path: &Path) -> Result<Vec<SnapshotEntry>, String> {
+    let snapshot = fs::read(path).map_err(|e| e.to_string())?;
+    let SnapshotVersion { version } =
+        serde_json::from_slice(&snapshot).map_err(|e| e.to_string())?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "Incompatible snapshot version {} (expected {})",
+            version, SNAPSHOT_VERSION
+        ));
+    }
+    let snapshot: Snapshot = serde_json::from_slice(&snapshot).map_err(|e| e.to_string())?;
+    Ok(snapshot.entries)
+}
+
+/// Bounds enforced by the periodic garbage collection of a [TilesCache]
+#[derive(Clone, Copy, Debug)]
+pub struct CacheLimits {
+    /// Drop tiles this long after they were fetched
+    pub max_age: Duration,
+    /// Drop tiles this long after they expired
+    pub expired_grace: Duration,
+    /// Evict the least recently used tiles beyond this many entries (0 for
+    /// no limit)
+    pub max_entries: usize,
+    /// Evict the least recently used tiles beyond this many bytes (0 for no
+    /// limit)
+    pub max_bytes: usize,
+}
+
+impl From<&Settings> for CacheLimits {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            max_age: Duration::from_secs(settings.tiles_max_age_secs),
+            expired_grace: Duration::from_secs(settings.tiles_cache_gc_grace_secs),
+            max_entries: settings.tiles_cache_max_entries,
+            max_bytes: settings.tiles_cache_max_bytes,
+        }
+    }
+}
+
+async fn tiles_cache_garbage_collect(cache: &TilesCache, limits: &CacheLimits, metrics: &Metrics) {
     trace!("tiles_cache_garbage_collect");
-    // calculate the size and GC (for seldomly used Tiles) while we're at it
+    // Drop the tiles that are past the hard maximum age
+    let count = cache.inner.len();
+    cache
+        .inner
+        .retain(|_, tiles_state| !tiles_state.too_old(limits.max_age));
+    metrics.count(
+        "tiles_cache.gc.max_age",
+        count.saturating_sub(cache.inner.len()) as i64,
+    );
+
+    // Drop the tiles expired past the grace period (those being refreshed
+    // are left to the refresh)
+    let count = cache.inner.len();
+    cache.inner.retain(|_, tiles_state| match tiles_state {
+        TilesState::Fresh { tiles } => !tiles.expired_since(limits.expired_grace),
+        _ => true,
+    });
+    metrics.count(
+        "tiles_cache.evicted.expired",
+        count.saturating_sub(cache.inner.len()) as i64,
+    );
+
+    // calculate the size and evict the least recently used tiles while over
+    // capacity
     let mut cache_count = 0;
     let mut cache_size = 0;
+    let mut candidates = Vec::new();
     for refm in cache.inner.iter() {
         cache_count += 1;
         cache_size += refm.value().size();
+        if let TilesState::Fresh { tiles } = refm.value() {
+            candidates.push((tiles.accessed(), refm.key().clone(), tiles.content.size()));
+        }
     }
+    let over_capacity = |count: usize, size: usize| {
+        (limits.max_entries > 0 && count > limits.max_entries)
+            || (limits.max_bytes > 0 && size > limits.max_bytes)
+    };
+    let mut evicted = 0;
+    if over_capacity(cache_count, cache_size) {
+        candidates.sort_unstable_by_key(|(accessed, _, _)| *accessed);
+        for (accessed, audience_key, size) in candidates {
+            if !over_capacity(cache_count, cache_size) {
+                break;
+            }
+            // Skip entries accessed (or replaced) since
+            if cache
+                .inner
+                .remove_if(&audience_key, |_, tiles_state| match tiles_state {
+                    TilesState::Fresh { tiles } => tiles.accessed() == accessed,
+                    _ => false,
+                })
+                .is_some()
+            {
+                cache_count -= 1;
+                cache_size = cache_size.saturating_sub(size);
+                evicted += 1;
+            }
+        }
+    }
+    metrics.count("tiles_cache.evicted.capacity", evicted);
 
-    metrics.count("tiles_cache.count", cache_count);
+    metrics.count("tiles_cache.count", cache_count as i64);
     metrics.count("tiles_cache.size", cache_size as i64);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audience_key() -> AudienceKey {
+        AudienceKey {
+            country_code: "US".to_owned(),
+            region_code: Some("WA".to_owned()),
+            dma_code: None,
+            form_factor: FormFactor::Desktop,
+            os_family: OsFamily::Windows,
+            legacy_only: false,
+        }
+    }
+
+    fn inflight(cache: &TilesCache, audience_key: &AudienceKey) -> Inflight {
+        match &*cache.get(audience_key).expect("No cache entry") {
+            TilesState::Populating { inflight } => inflight.clone(),
+            state => panic!("Unexpected state {:?}", state),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn populating_shared() {
+        let cache = TilesCache::new(10);
+        let audience_key = audience_key();
+        let handle = cache.prepare_write(&audience_key, false);
+        let inflight = inflight(&cache, &audience_key);
+
+        handle.insert(TilesState::Fresh {
+            tiles: Tiles::empty(60),
+        });
+        let tiles = inflight.await.expect("Populating cancelled");
+        assert!(matches!(tiles.content, TilesContent::Empty));
+    }
+
+    #[actix_rt::test]
+    async fn populating_cancelled() {
+        let cache = TilesCache::new(10);
+        let audience_key = audience_key();
+        let handle = cache.prepare_write(&audience_key, false);
+        let inflight = inflight(&cache, &audience_key);
+
+        // No write issued (e.g. the partner errored)
+        drop(handle);
+        assert!(inflight.await.is_err());
+        assert!(cache.get(&audience_key).is_none());
+    }
+
+    #[test]
+    fn audience_round_trip() {
+        let audience_key = AudienceKey {
+            dma_code: Some(819),
+            ..audience_key()
+        };
+        let location = audience_key.location().unwrap();
+        let device_info = audience_key.device_info();
+        assert_eq!(AudienceKey::new(&location, &device_info), audience_key);
+
+        let legacy = AudienceKey {
+            region_code: None,
+            legacy_only: true,
+            ..audience_key
+        };
+        let location = legacy.location().unwrap();
+        let device_info = legacy.device_info();
+        assert_eq!(AudienceKey::new(&location, &device_info), legacy);
+    }
+
+    #[test]
+    fn stale_if_error() {
+        let mut tiles = Tiles::empty(0);
+        assert!(tiles.expired());
+        assert!(tiles.stale_if_error(Duration::from_secs(60)));
+        assert!(!tiles.stale_if_error(Duration::from_secs(0)));
+        assert!(!tiles.too_old(Duration::from_secs(60)));
+
+        tiles.fetched -= Duration::from_secs(120);
+        assert!(tiles.too_old(Duration::from_secs(60)));
+    }
+
+    fn limits() -> CacheLimits {
+        CacheLimits {
+            max_age: Duration::from_secs(60 * 60),
+            expired_grace: Duration::from_secs(60),
+            max_entries: 0,
+            max_bytes: 0,
+        }
+    }
+
+    fn insert(cache: &TilesCache, region_code: &str, tiles: Tiles) -> AudienceKey {
+        let audience_key = AudienceKey {
+            region_code: Some(region_code.to_owned()),
+            ..audience_key()
+        };
+        cache
+            .inner
+            .insert(audience_key.clone(), TilesState::Fresh { tiles });
+        audience_key
+    }
+
+    #[test]
+    fn peek() {
+        let cache = TilesCache::new(10);
+        let tiles = Tiles::empty(60);
+        tiles.accessed.store(0, Ordering::Relaxed);
+        let audience_key = insert(&cache, "WA", tiles.clone());
+        assert!(cache.peek(&audience_key).is_some());
+        assert_eq!(tiles.accessed(), 0);
+        assert!(cache.get(&audience_key).is_some());
+        assert!(tiles.accessed() > 0);
+    }
+
+    #[test]
+    fn audience_keys() {
+        let cache = TilesCache::new(10);
+        let (wa, or, ca) = (Tiles::empty(60), Tiles::empty(60), Tiles::empty(60));
+        wa.accessed.store(2, Ordering::Relaxed);
+        or.accessed.store(3, Ordering::Relaxed);
+        ca.accessed.store(1, Ordering::Relaxed);
+        let wa = insert(&cache, "WA", wa);
+        let or = insert(&cache, "OR", or);
+        let ca = insert(&cache, "CA", ca);
+        // Most recently accessed first
+        assert_eq!(cache.audience_keys(), vec![or, wa, ca]);
+    }
+
+    #[actix_rt::test]
+    async fn gc_expired() {
+        let cache = TilesCache::new(10);
+        let fresh = insert(&cache, "WA", Tiles::empty(60));
+        // Expired, but within the grace period
+        let expired = insert(&cache, "OR", Tiles::empty(0));
+        let mut tiles = Tiles::empty(0);
+        tiles.expiry -= Duration::from_secs(120);
+        let evicted = insert(&cache, "CA", tiles);
+
+        tiles_cache_garbage_collect(&cache, &limits(), &Metrics::noop()).await;
+        assert!(cache.get(&fresh).is_some());
+        assert!(cache.get(&expired).is_some());
+        assert!(cache.get(&evicted).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn gc_capacity() {
+        let cache = TilesCache::new(10);
+        let json = |len| TilesContent::Json("x".repeat(len).into());
+        let mut keys = Vec::new();
+        for (i, region_code) in ["WA", "OR", "CA"].iter().enumerate() {
+            let tiles = Tiles {
+                content: json(10),
+                ..Tiles::empty(60)
+            };
+            tiles.accessed.store(i as u64, Ordering::Relaxed);
+            keys.push(insert(&cache, region_code, tiles));
+        }
+        // Now the most recently accessed
+        cache.get(&keys[0]);
+
+        let limits = CacheLimits {
+            max_entries: 2,
+            ..limits()
+        };
+        tiles_cache_garbage_collect(&cache, &limits, &Metrics::noop()).await;
+        assert!(cache.inner.contains_key(&keys[0]));
+        assert!(!cache.inner.contains_key(&keys[1]));
+        assert!(cache.inner.contains_key(&keys[2]));
+
+        let limits = CacheLimits {
+            max_bytes: 15,
+            ..limits
+        };
+        tiles_cache_garbage_collect(&cache, &limits, &Metrics::noop()).await;
+        assert_eq!(cache.inner.len(), 1);
+        assert!(cache.inner.contains_key(&keys[0]));
+    }
+
+    #[actix_rt::test]
+    async fn snapshot() {
+        let path = std::env::temp_dir().join(format!(
+            "contile-cache-snapshot-{}.json",
+            std::process::id()
+        ));
+        let max_age = Duration::from_secs(60 * 60);
+        let timeout = Duration::from_secs(5);
+        let cache = TilesCache::new(10);
+        let fresh = insert(&cache, "WA", Tiles::empty(60));
+        let expired = insert(&cache, "OR", Tiles::empty(0));
+        // Populating entries aren't snapshotted
+        let _populating = cache.prepare_write(&audience_key(), false);
+        assert_eq!(cache.write_snapshot(&path).unwrap(), 2);
+
+        let loaded = TilesCache::new(10);
+        assert_eq!(
+            loaded.load_snapshot(&path, max_age, timeout).await.unwrap(),
+            1
+        );
+        assert!(loaded.inner.contains_key(&fresh));
+        assert!(!loaded.inner.contains_key(&expired));
+        // Past the hard maximum age
+        let loaded = TilesCache::new(10);
+        assert_eq!(
+            loaded
+                .load_snapshot(&path, Duration::from_secs(0), timeout)
+                .await
+                .unwrap(),
+            0
+        );
+
+        // Other versions are rejected
+        let snapshot = fs::read_to_string(&path).unwrap().replacen(
+            &format!(r#""version":{}"#, SNAPSHOT_VERSION),
+            r#""version":0"#,
+            1,
+        );
+        fs::write(&path, snapshot).unwrap();
+        assert!(loaded.load_snapshot(&path, max_age, timeout).await.is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.load_snapshot(&path, max_age, timeout).await.is_err());
+        assert!(loaded.inner.is_empty());
+    }
+
+    #[test]
+    fn refreshing_ahead() {
+        let cache = TilesCache::new(10);
+        let ahead = Duration::from_secs(60);
+        let accessed_within = Duration::from_secs(60);
+        let soon = insert(&cache, "WA", Tiles::empty(30));
+        // Expiring soon, but idle
+        let tiles = Tiles::empty(30);
+        tiles.accessed.store(0, Ordering::Relaxed);
+        insert(&cache, "CA", tiles);
+        // Recently accessed, but not expiring soon
+        insert(&cache, "OR", Tiles::empty(120));
+        assert_eq!(
+            cache.refresh_candidates(ahead, accessed_within),
+            vec![soon.clone()]
+        );
+
+        let handle = cache.prepare_refresh(&soon).expect("Not Fresh");
+        assert!(matches!(
+            *cache.get(&soon).unwrap(),
+            TilesState::Refreshing { .. }
+        ));
+        // Already refreshing
+        assert!(cache.prepare_refresh(&soon).is_none());
+        assert!(cache.refresh_candidates(ahead, accessed_within).is_empty());
+        // The refresh failed: back to Fresh
+        drop(handle);
+        assert!(matches!(
+            *cache.get(&soon).unwrap(),
+            TilesState::Fresh { .. }
+        ));
+
+        let handle = cache.prepare_refresh(&soon).expect("Not Fresh");
+        handle.insert(TilesState::Fresh {
+            tiles: Tiles::empty(120),
+        });
+        assert!(cache.refresh_candidates(ahead, accessed_within).is_empty());
+    }
+
+    #[test]
+    fn refreshing_idle() {
+        let cache = TilesCache::new(10);
+        let ahead = Duration::from_secs(60);
+        let accessed_within = Duration::from_secs(60);
+        let key = insert(&cache, "WA", Tiles::empty(30));
+        let refresh = || {
+            let handle = cache.prepare_refresh(&key).expect("Not Fresh");
+            handle.insert(TilesState::Fresh {
+                tiles: Tiles::empty(30),
+            });
+        };
+        refresh();
+        // Still recently accessed
+        assert_eq!(
+            cache.refresh_candidates(ahead, accessed_within),
+            vec![key.clone()]
+        );
+
+        // But no longer once idle, despite the refresh
+        if let TilesState::Fresh { tiles } = cache.inner.get(&key).unwrap().value() {
+            tiles.accessed.store(0, Ordering::Relaxed);
+        }
+        refresh();
+        assert!(cache.refresh_candidates(ahead, accessed_within).is_empty());
+    }
+
+    #[test]
+    fn key_dimensions() {
+        let dimensions = KeyDimensions::from_settings(&Settings {
+            tiles_cache_key_dimensions: Some(
+                r#"{"US": ["region", "dma"], "CA": ["region"], "*": []}"#.to_owned(),
+            ),
+            ..Default::default()
+        })
+        .unwrap();
+        let audience_key = AudienceKey {
+            dma_code: Some(819),
+            ..audience_key()
+        };
+        assert_eq!(
+            dimensions.audience_key(audience_key.clone(), |_| false),
+            audience_key
+        );
+        let ca = AudienceKey {
+            country_code: "CA".to_owned(),
+            region_code: Some("BC".to_owned()),
+            ..audience_key.clone()
+        };
+        assert_eq!(
+            dimensions.audience_key(ca.clone(), |_| false),
+            AudienceKey {
+                dma_code: None,
+                ..ca.clone()
+            }
+        );
+        // Unless an advertiser ignores the DMA
+        assert_eq!(dimensions.audience_key(ca.clone(), |dma| dma == 819), ca);
+        let location = dimensions.location(
+            AudienceKey {
+                country_code: "DE".to_owned(),
+                region_code: Some("BE".to_owned()),
+                ..audience_key.clone()
+            }
+            .location()
+            .unwrap(),
+            |_| false,
+        );
+        assert_eq!(location.country(), "DE");
+        assert_eq!(location.region, None);
+        assert_eq!(location.dma, None);
+
+        // Every dimension by default
+        let dimensions = KeyDimensions::from_settings(&Settings::default()).unwrap();
+        assert_eq!(
+            dimensions.audience_key(audience_key.clone(), |_| false),
+            audience_key
+        );
+        assert!(KeyDimensions::from_settings(&Settings {
+            tiles_cache_key_dimensions: Some(r#"{"US": ["city"]}"#.to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn etag() {
+        let tile_response = |url: &str| TileResponse {
+            tiles: vec![crate::adm::Tile {
+                id: 1,
+                name: "Example".to_owned(),
+                url: url.to_owned(),
+                click_url: "https://example.com/click".to_owned(),
+                image_url: "https://example.com/image.png".to_owned(),
+                image_size: None,
+                impression_url: "https://example.com/impression".to_owned(),
+                position: None,
+            }],
+        };
+        let tiles = Tiles::new(tile_response("https://example.com"), 60).unwrap();
+        let etag = tiles.etag().expect("No etag").to_owned();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        let same = Tiles::new(tile_response("https://example.com"), 30).unwrap();
+        assert_eq!(same.etag(), Some(etag.as_str()));
+        let other = Tiles::new(tile_response("https://example.org"), 60).unwrap();
+        assert_ne!(other.etag(), Some(etag.as_str()));
+        assert_eq!(Tiles::empty(60).etag(), None);
+
+        assert!(tiles.remaining() > Duration::from_secs(50));
+        assert_eq!(Tiles::empty(0).remaining(), Duration::from_secs(0));
+    }
+
+    /// Tiles of the named advertisers
+    fn tiles(names: &[&str]) -> Tiles {
+        let tiles = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| crate::adm::Tile {
+                id: i as u64,
+                name: (*name).to_owned(),
+                url: "https://example.com".to_owned(),
+                click_url: "https://example.com/click".to_owned(),
+                image_url: "https://example.com/image.png".to_owned(),
+                image_size: None,
+                impression_url: "https://example.com/impression".to_owned(),
+                position: None,
+            })
+            .collect();
+        Tiles::new(TileResponse { tiles }, 60).unwrap()
+    }
+
+    #[test]
+    fn invalidate_advertisers() {
+        let cache = TilesCache::new(10);
+        let acme = insert(&cache, "WA", tiles(&["Acme", "Dunder Mifflin"]));
+        let dunder = insert(&cache, "OR", tiles(&["Dunder Mifflin"]));
+        let empty = insert(&cache, "CA", Tiles::empty(60));
+        assert!(cache
+            .get(&acme)
+            .unwrap()
+            .tiles()
+            .unwrap()
+            .has_advertiser("ACME"));
+
+        assert!(cache.invalidate_advertisers(&HashSet::new()).is_empty());
+        let changed: HashSet<_> = vec!["acme".to_owned(), "initech".to_owned()]
+            .into_iter()
+            .collect();
+        assert_eq!(cache.invalidate_advertisers(&changed), vec![acme.clone()]);
+        assert!(cache.get(&acme).is_none());
+        assert!(cache.get(&dunder).is_some());
+        assert!(cache.get(&empty).is_some());
+    }
+
+    #[actix_rt::test]
+    async fn invalidate_inflight() {
+        let cache = TilesCache::new(10);
+        let audience_key = audience_key();
+        let unrelated = AudienceKey {
+            region_code: Some("OR".to_owned()),
+            ..audience_key.clone()
+        };
+        let handle = cache.prepare_write(&audience_key, false);
+        let unrelated_handle = cache.prepare_write(&unrelated, false);
+        let waiting = inflight(&cache, &audience_key);
+
+        let changed: HashSet<_> = vec!["acme".to_owned()].into_iter().collect();
+        cache.invalidate_advertisers(&changed);
+        // Possibly filtered with the outdated settings
+        handle.insert(TilesState::Fresh {
+            tiles: tiles(&["Acme", "Dunder Mifflin"]),
+        });
+        assert!(cache.get(&audience_key).is_none());
+        assert!(waiting.await.is_err());
+        // Writes without the advertiser aren't affected
+        unrelated_handle.insert(TilesState::Fresh {
+            tiles: tiles(&["Dunder Mifflin"]),
+        });
+        assert!(cache.get(&unrelated).is_some());
+
+        // Nor are writes prepared afterwards
+        let handle = cache.prepare_write(&audience_key, false);
+        handle.insert(TilesState::Fresh {
+            tiles: tiles(&["Acme"]),
+        });
+        assert!(cache.get(&audience_key).is_some());
+    }
+
+    #[test]
+    fn invalidate_shared() {
+        let cache = TilesCache::new(10);
+        // Other instances' tiles fetched before the invalidation
+        let stale = tiles(&["Acme"]);
+        let unrelated = tiles(&["Dunder Mifflin"]);
+        let changed: HashSet<_> = vec!["acme".to_owned()].into_iter().collect();
+        cache.invalidate_advertisers(&changed);
+        assert!(cache.invalidated_after_fetch(&stale));
+        // Only if they include the advertiser
+        assert!(!cache.invalidated_after_fetch(&unrelated));
+        // Or were fetched afterwards
+        assert!(!cache.invalidated_after_fetch(&tiles(&["Acme"])));
+    }
+
+    #[test]
+    fn payload_encodings() {
+        use std::io::Read;
+
+        let json = r#"{"tiles":[]}"#.repeat(10);
+        let payload = JsonPayload::from(json.clone());
+        let mut gunzipped = String::new();
+        flate2::read::GzDecoder::new(payload.encoded(Encoding::Gzip).unwrap())
+            .read_to_string(&mut gunzipped)
+            .unwrap();
+        assert_eq!(gunzipped, json);
+        let mut unbrotlied = String::new();
+        brotli::Decompressor::new(payload.encoded(Encoding::Brotli).unwrap(), 4096)
+            .read_to_string(&mut unbrotlied)
+            .unwrap();
+        assert_eq!(unbrotlied, json);
+        assert!(payload.size() > json.len());
+
+        // Stored along with the compressed variants
+        let stored = serde_json::to_string(&TilesContent::Json(payload.clone())).unwrap();
+        match serde_json::from_str(&stored).unwrap() {
+            TilesContent::Json(read) => {
+                assert_eq!(read.json(), json);
+                assert_eq!(
+                    read.encoded(Encoding::Gzip),
+                    payload.encoded(Encoding::Gzip)
+                );
+                assert_eq!(
+                    read.encoded(Encoding::Brotli),
+                    payload.encoded(Encoding::Brotli)
+                );
+            }
+            TilesContent::Empty => panic!("Empty content"),
+        }
+
+        // Stored as only the JSON, compressed when read back
+        let stored = serde_json::to_string(&serde_json::json!({ "Json": json })).unwrap();
+        match serde_json::from_str(&stored).unwrap() {
+            TilesContent::Json(read) => assert!(read.encoded(Encoding::Brotli).is_some()),
+            TilesContent::Empty => panic!("Empty content"),
+        }
+    }
+}
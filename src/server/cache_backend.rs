@@ -0,0 +1,256 @@
+//! Tiles cache backends shared between instances
+//!
+//! The [crate::server::cache::TilesCache] keeps every instance's tiles in a
+//! local map. When a [TilesCacheBackend] is configured (via
+//! `tiles_cache_backend`) instances additionally share their tiles through
+//! it: an instance missing an audience's tiles first checks the backend,
+//! and only fetches them from the partner while holding the audience's
+//! [Lease], so only one instance refreshes an entry at a time.
+#[cfg(test)]
+use std::time::Instant;
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+#[cfg(test)]
+use dashmap::{mapref::entry::Entry, DashMap};
+use rand::{thread_rng, Rng};
+
+use crate::{
+    error::{HandlerError, HandlerResult},
+    server::{
+        cache::{AudienceKey, Tiles},
+        redis_backend::RedisBackend,
+    },
+    settings::Settings,
+};
+
+/// The exclusive right to refresh an audience's tiles, until it's released
+/// or expires
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    pub audience_key: AudienceKey,
+    /// Identifies the lease's holder
+    pub token: String,
+}
+
+impl Lease {
+    pub fn new(audience_key: &AudienceKey) -> Self {
+        Self {
+            audience_key: audience_key.clone(),
+            token: format!("{:016x}", thread_rng().gen::<u64>()),
+        }
+    }
+}
+
+/// Storage for tiles shared between instances
+#[async_trait(?Send)]
+pub trait TilesCacheBackend: Debug + Send + Sync {
+    /// Read an audience's tiles
+    async fn get(&self, audience_key: &AudienceKey) -> HandlerResult<Option<Tiles>>;
+
+    /// Store an audience's tiles, dropping them after `ttl`
+    async fn set(
+        &self,
+        audience_key: &AudienceKey,
+        tiles: &Tiles,
+        ttl: Duration,
+    ) -> HandlerResult<()>;
+
+    /// Remove an audience's tiles
+    async fn delete(&self, audience_key: &AudienceKey) -> HandlerResult<()>;
+
+    /// Attempt to acquire the lease to refresh an audience's tiles, held
+    /// for up to `ttl`. `None` when another holder has it
+    async fn try_lease(
+        &self,
+        audience_key: &AudienceKey,
+        ttl: Duration,
+    ) -> HandlerResult<Option<Lease>>;
+
+    /// Release a lease (if it's still held)
+    async fn release(&self, lease: &Lease) -> HandlerResult<()>;
+}
+
+/// A backend shared within the process, standing in for a shared one in
+/// tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    tiles: DashMap<AudienceKey, (Tiles, Instant)>,
+    leases: DashMap<AudienceKey, (String, Instant)>,
+}
+
+#[cfg(test)]
+#[async_trait(?Send)]
+impl TilesCacheBackend for MemoryBackend {
+    async fn get(&self, audience_key: &AudienceKey) -> HandlerResult<Option<Tiles>> {
+        let now = Instant::now();
+        self.tiles
+            .remove_if(audience_key, |_, (_, until)| *until <= now);
+        Ok(self
+            .tiles
+            .get(audience_key)
+            .map(|entry| entry.value().0.clone()))
+    }
+
+    async fn set(
+        &self,
+        audience_key: &AudienceKey,
+        tiles: &Tiles,
+        ttl: Duration,
+    ) -> HandlerResult<()> {
+        self.tiles
+            .insert(audience_key.clone(), (tiles.clone(), Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn delete(&self, audience_key: &AudienceKey) -> HandlerResult<()> {
+        self.tiles.remove(audience_key);
+        Ok(())
+    }
+
+    async fn try_lease(
+        &self,
+        audience_key: &AudienceKey,
+        ttl: Duration,
+    ) -> HandlerResult<Option<Lease>> {
+        let now = Instant::now();
+        let lease = Lease::new(audience_key);
+        let acquired = match self.leases.entry(audience_key.clone()) {
+            Entry::Occupied(mut entry) => {
+                // Expired leases may be taken over
+                if entry.get().1 <= now {
+                    entry.insert((lease.token.clone(), now + ttl));
+                    true
+                } else {
+                    false
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((lease.token.clone(), now + ttl));
+                true
+            }
+        };
+        Ok(if acquired { Some(lease) } else { None })
+    }
+
+    async fn release(&self, lease: &Lease) -> HandlerResult<()> {
+        self.leases
+            .remove_if(&lease.audience_key, |_, (token, _)| *token == lease.token);
+        Ok(())
+    }
+}
+
+/// Build the backend configured by `tiles_cache_backend`: a `redis://` URL.
+/// `None` when unset
+pub fn backend_from_settings(
+    settings: &Settings,
+) -> HandlerResult<Option<Arc<dyn TilesCacheBackend>>> {
+    let backend: Arc<dyn TilesCacheBackend> = match settings.tiles_cache_backend.as_deref() {
+        None | Some("") => return Ok(None),
+        Some(url) if url.starts_with("redis://") => Arc::new(RedisBackend::new(url, settings)?),
+        Some(other) => {
+            return Err(HandlerError::internal(&format!(
+                "Invalid tiles_cache_backend: {:?}",
+                other
+            )))
+        }
+    };
+    Ok(Some(backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::{FormFactor, OsFamily};
+
+    fn audience_key() -> AudienceKey {
+        AudienceKey {
+            country_code: "US".to_owned(),
+            region_code: Some("WA".to_owned()),
+            dma_code: None,
+            form_factor: FormFactor::Desktop,
+            os_family: OsFamily::Windows,
+            legacy_only: false,
+        }
+    }
+
+    #[test]
+    fn from_settings() {
+        let backend = |url: &str| {
+            backend_from_settings(&Settings {
+                tiles_cache_backend: Some(url.to_owned()),
+                ..Default::default()
+            })
+        };
+        assert!(backend("").unwrap().is_none());
+        assert!(backend("redis://localhost:6379").unwrap().is_some());
+        // Only shared within the process: not a shared backend
+        assert!(backend("memory").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn memory_leases() {
+        let backend = MemoryBackend::default();
+        let audience_key = audience_key();
+        let ttl = Duration::from_secs(60);
+        let lease = backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .expect("No lease");
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_none());
+        // Releasing another holder's lease doesn't release ours
+        backend.release(&Lease::new(&audience_key)).await.unwrap();
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_none());
+        backend.release(&lease).await.unwrap();
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_some());
+
+        // Expired leases may be taken over
+        let other = AudienceKey {
+            region_code: None,
+            ..audience_key
+        };
+        assert!(backend
+            .try_lease(&other, Duration::from_secs(0))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(backend.try_lease(&other, ttl).await.unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn memory_tiles() {
+        let backend = MemoryBackend::default();
+        let audience_key = audience_key();
+        assert!(backend.get(&audience_key).await.unwrap().is_none());
+        backend
+            .set(&audience_key, &Tiles::empty(60), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(backend.get(&audience_key).await.unwrap().is_some());
+        backend
+            .set(&audience_key, &Tiles::empty(60), Duration::from_secs(0))
+            .await
+            .unwrap();
+        assert!(backend.get(&audience_key).await.unwrap().is_none());
+        backend
+            .set(&audience_key, &Tiles::empty(60), Duration::from_secs(60))
+            .await
+            .unwrap();
+        backend.delete(&audience_key).await.unwrap();
+        assert!(backend.get(&audience_key).await.unwrap().is_none());
+    }
+}
@@ -1,25 +1,30 @@
 //! Main application server
+use std::future::Future;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
 use actix_cors::Cors;
-use actix_web::{
-    dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpServer,
-};
+use actix_web::{http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpServer};
 use cadence::StatsdClient;
 
 use crate::{
-    adm::{spawn_updater, AdmFilter},
+    adm::{spawn_updater, AdmFilter, LiveUpdater},
     error::{HandlerError, HandlerResult},
-    metrics::metrics_from_opts,
-    server::{img_storage::ImageStore, location::location_config_from_settings},
+    metrics::{metrics_from_opts, Metrics},
+    providers::{providers_from_settings, Hedger, RateLimiter, TileProvider},
+    server::{img_storage::ImageStore, location::location_config_from_settings, warmup::Warmup},
     settings::Settings,
-    web::{dockerflow, handlers, middleware},
+    web::{admin, dockerflow, handlers, middleware},
 };
 
 pub mod cache;
+pub mod cache_backend;
 pub mod img_storage;
 pub mod location;
+pub mod redis_backend;
+pub mod refresher;
+pub mod warmup;
 
 /// Arbitrary initial cache size based on the expected mean, feel free to
 /// adjust
@@ -36,7 +41,19 @@ pub struct ServerState {
     pub reqwest_client: reqwest::Client,
     pub tiles_cache: cache::TilesCache,
     pub settings: Settings,
+    /// The configured sources of tiles
+    pub providers: Vec<Arc<dyn TileProvider>>,
     pub filter: Arc<RwLock<AdmFilter>>,
+    /// On demand filter updates (when `adm_live_update` is enabled)
+    pub live_updater: Option<LiveUpdater>,
+    /// Hedges slow partner requests (when `partner_hedge` is enabled)
+    pub hedger: Arc<Hedger>,
+    /// Limits outbound partner requests
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Prefetches common audiences' tiles at startup
+    pub warmup: Warmup,
+    /// The tiles cache key dimensions of each country
+    pub key_dimensions: Arc<cache::KeyDimensions>,
     pub img_store: Option<ImageStore>,
     pub excluded_dmas: Option<Vec<u16>>,
     pub start_up: Instant,
@@ -49,7 +66,13 @@ impl Clone for ServerState {
             reqwest_client: self.reqwest_client.clone(),
             tiles_cache: self.tiles_cache.clone(),
             settings: self.settings.clone(),
+            providers: self.providers.clone(),
             filter: self.filter.clone(),
+            live_updater: self.live_updater.clone(),
+            hedger: self.hedger.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            warmup: self.warmup.clone(),
+            key_dimensions: self.key_dimensions.clone(),
             img_store: self.img_store.clone(),
             excluded_dmas: self.excluded_dmas.clone(),
             start_up: self.start_up,
@@ -63,6 +86,7 @@ impl std::fmt::Debug for ServerState {
             .field("metrics", &self.metrics)
             .field("adm_endpoint_url", &self.settings.adm_endpoint_url)
             .field("adm_mobile_endpoint_url", &self.settings.adm_endpoint_url)
+            .field("providers", &self.providers)
             .field("reqwest_client", &self.reqwest_client)
             .field("tiles_cache", &self.tiles_cache)
             .finish()
@@ -94,6 +118,8 @@ macro_rules! build_app {
             .service(web::resource("/v1/tiles").route(web::get().to(handlers::get_tiles)))
             // image cache tester...
             //.service(web::resource("/v1/test").route(web::get().to(handlers::get_image)))
+            // Operator access to the cache (disabled unless configured)
+            .service(web::scope("/__admin__").configure(admin::service))
             // And finally the behavior necessary to satisfy Dockerflow
             .service(web::scope("/").configure(dockerflow::service))
     };
@@ -101,12 +127,17 @@ macro_rules! build_app {
 
 impl Server {
     /// initialize a new instance of the server from [Settings]
-    pub async fn with_settings(mut settings: Settings) -> Result<dev::Server, HandlerError> {
+    ///
+    /// The returned future resolves when the server has stopped (after
+    /// snapshotting the tiles cache, if configured).
+    pub async fn with_settings(
+        mut settings: Settings,
+    ) -> Result<impl Future<Output = std::io::Result<()>>, HandlerError> {
         let metrics = metrics_from_opts(&settings)?;
         let mut raw_filter = HandlerResult::<AdmFilter>::from(&mut settings)?;
         // try to update from the bucket if possible.
         if raw_filter.is_cloud() {
-            raw_filter.update().await?
+            raw_filter.update().await?;
         }
         let filter = Arc::new(RwLock::new(raw_filter));
         let req = reqwest::Client::builder()
@@ -114,8 +145,27 @@ impl Server {
             .timeout(Duration::from_secs(settings.request_timeout))
             .user_agent(REQWEST_USER_AGENT)
             .build()?;
-        spawn_updater(&filter, req.clone());
-        let tiles_cache = cache::TilesCache::new(TILES_CACHE_INITIAL_CAPACITY);
+        let tiles_cache = cache::TilesCache::new(TILES_CACHE_INITIAL_CAPACITY)
+            .with_backend(cache_backend::backend_from_settings(&settings)?);
+        spawn_updater(&filter, req.clone(), &tiles_cache, &metrics);
+        let live_updater = LiveUpdater::from_settings(&settings, &filter, &req, &tiles_cache);
+        if let Some(path) = &settings.tiles_cache_snapshot {
+            match tiles_cache
+                .load_snapshot(
+                    Path::new(path),
+                    Duration::from_secs(settings.tiles_max_age_secs),
+                    Duration::from_millis(settings.tiles_cache_snapshot_load_ms),
+                )
+                .await
+            {
+                Ok(count) => {
+                    info!("Loaded {} tiles cache entries from {}", count, path);
+                    Metrics::from(&metrics).count("tiles_cache.snapshot.loaded", count as i64);
+                }
+                // Don't prevent startup over a missing or broken snapshot
+                Err(e) => warn!("Couldn't load tiles cache snapshot {}: {:?}", path, e),
+            }
+        }
         let img_store = ImageStore::create(&settings, &metrics, &req).await?;
         let excluded_dmas = if let Some(exclude_dmas) = &settings.exclude_dma {
             serde_json::from_str(exclude_dmas).map_err(|e| {
@@ -124,19 +174,33 @@ impl Server {
         } else {
             None
         };
+        let providers = providers_from_settings(&settings)?;
         let state = ServerState {
             metrics: Box::new(metrics.clone()),
             reqwest_client: req,
             tiles_cache: tiles_cache.clone(),
             settings: settings.clone(),
+            providers,
             filter,
+            live_updater,
+            hedger: Arc::new(Hedger::new(&settings)),
+            rate_limiter: Arc::new(RateLimiter::new(&settings)),
+            warmup: Warmup::from_settings(&settings)?,
+            key_dimensions: Arc::new(cache::KeyDimensions::from_settings(&settings)?),
             img_store,
             excluded_dmas,
             start_up: Instant::now(),
         };
         let location_config = location_config_from_settings(&settings, &metrics);
 
-        tiles_cache.spawn_periodic_reporter(Duration::from_secs(60), metrics.clone());
+        tiles_cache.spawn_periodic_reporter(
+            Duration::from_secs(60),
+            cache::CacheLimits::from(&settings),
+            metrics.clone(),
+        );
+        state.warmup.spawn(&state);
+        refresher::spawn(&state);
+        warmup::spawn_snapshotter(&tiles_cache, &settings);
 
         let mut server = HttpServer::new(move || build_app!(state.clone(), location_config));
         if let Some(keep_alive) = settings.actix_keep_alive {
@@ -146,6 +210,16 @@ impl Server {
             .bind((settings.host, settings.port))
             .expect("Could not get Server in Server::with_settings")
             .run();
-        Ok(server)
+        let snapshot = settings.tiles_cache_snapshot;
+        Ok(async move {
+            let result = server.await;
+            if let Some(path) = snapshot {
+                match tiles_cache.write_snapshot(Path::new(&path)) {
+                    Ok(count) => info!("Wrote {} tiles cache entries to {}", count, path),
+                    Err(e) => warn!("Couldn't write tiles cache snapshot {}: {:?}", path, e),
+                }
+            }
+            result
+        })
     }
 }
@@ -0,0 +1,330 @@
+//! A [TilesCacheBackend] backed by Redis
+//!
+//! Tiles are stored as JSON under `contile:tiles:{audience}` keys, leases
+//! under `contile:lease:{audience}` keys (set with `NX` so only one holder
+//! acquires them, and `PX` so they expire should the holder die).
+//!
+//! Commands are pipelined over a single multiplexed connection, made lazily
+//! and remade after a failure. Failed commands aren't retried: whether they
+//! were applied is unknown (and commands such as `SET NX` aren't
+//! idempotent), so the caller falls back to the partner instead.
+use std::{fmt, future::Future, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, Client, RedisError, Script};
+
+use crate::{
+    error::{HandlerError, HandlerResult},
+    server::{
+        cache::{AudienceKey, Tiles},
+        cache_backend::{Lease, TilesCacheBackend},
+    },
+    settings::Settings,
+};
+
+/// Deletes a lease (`KEYS[1]`) only if it's still held by its token
+/// (`ARGV[1]`)
+const RELEASE_SCRIPT: &str = "if redis.call('get',KEYS[1])==ARGV[1] then \
+                              return redis.call('del',KEYS[1]) else return 0 end";
+
+pub struct RedisBackend {
+    client: Client,
+    /// Timeout for connecting and for each command
+    timeout: Duration,
+    /// The connection shared by every command (`None` until connected, or
+    /// after a failure)
+    conn: Mutex<Option<MultiplexedConnection>>,
+    release_script: Script,
+}
+
+impl fmt::Debug for RedisBackend {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("RedisBackend")
+            .field("addr", &self.client.get_connection_info().addr)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl RedisBackend {
+    /// Build a backend for a `redis://[:password@]host[:port][/db]` URL.
+    /// Connections are made lazily
+    pub fn new(url: &str, settings: &Settings) -> HandlerResult<Self> {
+        let client = Client::open(url).map_err(|e| {
+            HandlerError::internal(&format!("Invalid tiles_cache_backend {:?}: {}", url, e))
+        })?;
+        Ok(Self {
+            client,
+            timeout: Duration::from_millis(settings.tiles_cache_backend_timeout_ms),
+            conn: Mutex::new(None),
+            release_script: Script::new(RELEASE_SCRIPT),
+        })
+    }
+
+    /// The shared connection, connecting if needed
+    async fn connection(&self) -> HandlerResult<MultiplexedConnection> {
+        if let Some(conn) = self.conn.lock().unwrap().as_ref() {
+            return Ok(conn.clone());
+        }
+        let conn = self
+            .timed(self.client.get_multiplexed_tokio_connection())
+            .await?;
+        // A concurrent command may have connected meanwhile: either will do
+        *self.conn.lock().unwrap() = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Run a command on the shared connection, dropping the connection
+    /// should it fail (it's remade by the next command)
+    async fn run<T, F, Fut>(&self, command: F) -> HandlerResult<T>
+    where
+        F: FnOnce(MultiplexedConnection) -> Fut,
+        Fut: Future<Output = Result<T, RedisError>>,
+    {
+        let conn = self.connection().await?;
+        let result = self.timed(command(conn)).await;
+        if let Err(e) = &result {
+            trace!("RedisBackend: dropping connection after {:?}", e);
+            self.conn.lock().unwrap().take();
+        }
+        result
+    }
+
+    async fn timed<T, Fut>(&self, future: Fut) -> HandlerResult<T>
+    where
+        Fut: Future<Output = Result<T, RedisError>>,
+    {
+        match actix_rt::time::timeout(self.timeout, future).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(HandlerError::internal(&format!("Redis failure: {}", e))),
+            Err(_) => Err(HandlerError::internal("Redis failure: timed out")),
+        }
+    }
+}
+
+/// The key identifying an audience
+fn audience(audience_key: &AudienceKey) -> String {
+    format!(
+        "{}:{}:{}:{}:{}:{}",
+        audience_key.country_code,
+        audience_key.region_code.as_deref().unwrap_or_default(),
+        audience_key
+            .dma_code
+            .map(|dma| dma.to_string())
+            .unwrap_or_default(),
+        audience_key.form_factor,
+        audience_key.os_family,
+        audience_key.legacy_only as u8
+    )
+}
+
+fn tiles_key(audience_key: &AudienceKey) -> String {
+    format!("contile:tiles:{}", audience(audience_key))
+}
+
+fn lease_key(audience_key: &AudienceKey) -> String {
+    format!("contile:lease:{}", audience(audience_key))
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().max(1) as u64
+}
+
+#[async_trait(?Send)]
+impl TilesCacheBackend for RedisBackend {
+    async fn get(&self, audience_key: &AudienceKey) -> HandlerResult<Option<Tiles>> {
+        let key = tiles_key(audience_key);
+        let data: Option<Vec<u8>> = self
+            .run(|mut conn| async move { redis::cmd("GET").arg(key).query_async(&mut conn).await })
+            .await?;
+        data.map(|data| {
+            serde_json::from_slice(&data)
+                .map_err(|e| HandlerError::internal(&format!("Invalid cached tiles: {:?}", e)))
+        })
+        .transpose()
+    }
+
+    async fn set(
+        &self,
+        audience_key: &AudienceKey,
+        tiles: &Tiles,
+        ttl: Duration,
+    ) -> HandlerResult<()> {
+        let key = tiles_key(audience_key);
+        let data = serde_json::to_vec(tiles)
+            .map_err(|e| HandlerError::internal(&format!("Tiles failed to serialize: {:?}", e)))?;
+        self.run(|mut conn| async move {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(data)
+                .arg("PX")
+                .arg(millis(ttl))
+                .query_async(&mut conn)
+                .await
+        })
+        .await
+    }
+
+    async fn delete(&self, audience_key: &AudienceKey) -> HandlerResult<()> {
+        let key = tiles_key(audience_key);
+        self.run(|mut conn| async move { redis::cmd("DEL").arg(key).query_async(&mut conn).await })
+            .await
+    }
+
+    async fn try_lease(
+        &self,
+        audience_key: &AudienceKey,
+        ttl: Duration,
+    ) -> HandlerResult<Option<Lease>> {
+        let key = lease_key(audience_key);
+        let lease = Lease::new(audience_key);
+        let token = lease.token.clone();
+        // A nil reply when another holder has it
+        let reply: Option<String> = self
+            .run(|mut conn| async move {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(millis(ttl))
+                    .query_async(&mut conn)
+                    .await
+            })
+            .await?;
+        Ok(reply.map(|_| lease))
+    }
+
+    async fn release(&self, lease: &Lease) -> HandlerResult<()> {
+        let key = lease_key(&lease.audience_key);
+        let mut invocation = self.release_script.prepare_invoke();
+        invocation.key(&key).arg(&lease.token);
+        // Atomically, so a lease that expired and was acquired by another
+        // holder isn't released
+        let released: i64 = self
+            .run(|mut conn| async move { invocation.invoke_async(&mut conn).await })
+            .await?;
+        if released == 0 {
+            trace!("RedisBackend: lease expired before its release: {}", key);
+        }
+        Ok(())
+    }
+}
+
+/// Tests against the Redis server at `CONTILE_TEST_REDIS_URL` (e.g.
+/// `redis://127.0.0.1:6379/1`), skipped when unset. Its data is clobbered
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::{FormFactor, OsFamily};
+
+    const TEST_REDIS_URL: &str = "CONTILE_TEST_REDIS_URL";
+
+    fn backend() -> Option<RedisBackend> {
+        match std::env::var(TEST_REDIS_URL) {
+            Ok(url) => Some(RedisBackend::new(&url, &Settings::default()).unwrap()),
+            Err(_) => {
+                eprintln!("{} unset: skipping Redis test", TEST_REDIS_URL);
+                None
+            }
+        }
+    }
+
+    fn audience_key() -> AudienceKey {
+        AudienceKey {
+            country_code: "US".to_owned(),
+            region_code: Some("WA".to_owned()),
+            dma_code: Some(819),
+            form_factor: FormFactor::Desktop,
+            os_family: OsFamily::Windows,
+            legacy_only: false,
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let settings = Settings::default();
+        assert!(RedisBackend::new("redis://:secret@localhost/2", &settings).is_ok());
+        assert!(RedisBackend::new("localhost:6379", &settings).is_err());
+        assert_eq!(
+            tiles_key(&audience_key()),
+            "contile:tiles:US:WA:819:desktop:windows:0"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn tiles() {
+        let backend = match backend() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let audience_key = audience_key();
+        backend.delete(&audience_key).await.unwrap();
+        assert!(backend.get(&audience_key).await.unwrap().is_none());
+        backend
+            .set(&audience_key, &Tiles::empty(60), Duration::from_secs(60))
+            .await
+            .unwrap();
+        let tiles = backend.get(&audience_key).await.unwrap().expect("No tiles");
+        assert!(!tiles.expired());
+        backend.delete(&audience_key).await.unwrap();
+        assert!(backend.get(&audience_key).await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn leases() {
+        let backend = match backend() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let audience_key = AudienceKey {
+            dma_code: None,
+            ..audience_key()
+        };
+        let ttl = Duration::from_secs(60);
+        let lease = backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .expect("No lease");
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_none());
+        backend.release(&Lease::new(&audience_key)).await.unwrap();
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_none());
+        backend.release(&lease).await.unwrap();
+        let lease = backend
+            .try_lease(&audience_key, Duration::from_millis(1))
+            .await
+            .unwrap()
+            .expect("No lease");
+        // Expired
+        actix_rt::time::delay_for(Duration::from_millis(5)).await;
+        let other = backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .expect("No lease");
+        // No longer ours to release
+        backend.release(&lease).await.unwrap();
+        assert!(backend
+            .try_lease(&audience_key, ttl)
+            .await
+            .unwrap()
+            .is_none());
+        backend.release(&other).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn unavailable() {
+        let backend = RedisBackend::new("redis://127.0.0.1:1", &Settings::default()).unwrap();
+        assert!(backend.get(&audience_key()).await.is_err());
+        assert!(backend.conn.lock().unwrap().is_none());
+    }
+}
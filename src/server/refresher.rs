@@ -0,0 +1,76 @@
+//! Background refresh of tiles ahead of their expiry
+//!
+//! Expired tiles are otherwise only refreshed once a request finds them
+//! expired, that request paying the partner's latency. When enabled
+//! (`tiles_refresh_ahead_secs`), every `tiles_refresh_interval_ms` the tiles
+//! of audiences accessed within the last `tiles_refresh_accessed_secs` that
+//! expire within `tiles_refresh_ahead_secs` are re-fetched in the
+//! background, up to `tiles_refresh_concurrency` at a time. Requests are
+//! served the current tiles meanwhile (the entries are Refreshing).
+//! Audiences another instance is already refreshing are skipped.
+use std::{cell::Cell, time::Duration};
+
+use futures::stream::{self, StreamExt};
+
+use crate::{
+    metrics::Metrics,
+    server::{
+        warmup::{populate, Populated},
+        ServerState,
+    },
+};
+
+/// Periodically refresh the hot tiles about to expire (unless disabled)
+pub fn spawn(state: &ServerState) {
+    if state.settings.tiles_refresh_ahead_secs == 0 {
+        return;
+    }
+    let state = state.clone();
+    actix_rt::spawn(async move {
+        let interval = Duration::from_millis(state.settings.tiles_refresh_interval_ms);
+        loop {
+            actix_rt::time::delay_for(interval).await;
+            refresh(&state).await;
+        }
+    });
+}
+
+pub async fn refresh(state: &ServerState) {
+    let settings = &state.settings;
+    let ahead = Duration::from_secs(settings.tiles_refresh_ahead_secs);
+    let audiences = state.tiles_cache.refresh_candidates(
+        ahead,
+        Duration::from_secs(settings.tiles_refresh_accessed_secs),
+    );
+    if audiences.is_empty() {
+        return;
+    }
+    trace!("Refresher: refreshing {} audiences", audiences.len());
+    let metrics = Metrics::from(state);
+    let (completed, failed, skipped) = (Cell::new(0), Cell::new(0), Cell::new(0));
+    stream::iter(audiences.iter())
+        .for_each_concurrent(settings.tiles_refresh_concurrency.max(1), |key| {
+            let (metrics, completed, failed, skipped) = (&metrics, &completed, &failed, &skipped);
+            async move {
+                // A request may have begun refreshing it meanwhile
+                let handle = match state.tiles_cache.prepare_refresh(key) {
+                    Some(handle) => handle,
+                    None => return,
+                };
+                // Another instance's tiles due for a refresh themselves won't do
+                match populate(state, key, handle, ahead, metrics).await {
+                    Ok(Populated::Written) => completed.set(completed.get() + 1),
+                    Ok(Populated::Busy) => skipped.set(skipped.get() + 1),
+                    Err(e) => {
+                        // Left for a request to refresh once expired
+                        trace!("Refresher: {:?} failed: {:?}", key, e);
+                        failed.set(failed.get() + 1);
+                    }
+                }
+            }
+        })
+        .await;
+    metrics.count("tiles_cache.refresh.completed", completed.get());
+    metrics.count("tiles_cache.refresh.failed", failed.get());
+    metrics.count("tiles_cache.refresh.skipped", skipped.get());
+}
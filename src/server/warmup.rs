@@ -0,0 +1,350 @@
+//! Tiles cache warmup
+//!
+//! Prefetches the tiles of common audiences in the background at startup,
+//! so a freshly deployed server doesn't answer its first requests with 204s
+//! (while swamping the partner).
+//!
+//! The audiences are read from `tiles_warmup_audiences` followed by the
+//! snapshot of recent traffic at `tiles_warmup_snapshot` (both a JSON list of
+//! [AudienceKey]s, e.g. `[{"country_code": "US", "region_code": "WA",
+//! "form_factor": "desktop", "os_family": "windows"}]`). The snapshot is
+//! rewritten every `tiles_warmup_snapshot_interval_secs` from the audiences
+//! then in the cache, the most recently accessed first.
+//!
+//! Audiences another instance is already fetching (via the shared cache
+//! backend) are skipped.
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    error::{HandlerError, HandlerResult},
+    metrics::Metrics,
+    providers,
+    server::{
+        cache::{AudienceKey, SharedLookup, Tiles, TilesCache, TilesState, WriteHandle},
+        ServerState,
+    },
+    settings::Settings,
+    tags::Tags,
+    web::handlers::add_jitter,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WarmupState {
+    /// No audiences to warm up
+    Disabled,
+    Pending,
+    Running,
+    Complete,
+}
+
+/// Warmup progress, as reported by `__heartbeat__`
+#[derive(Clone, Debug, Serialize)]
+pub struct WarmupStatus {
+    pub state: WarmupState,
+    /// Number of audiences to prefetch
+    pub total: usize,
+    /// Number of audiences processed so far (including those that failed or
+    /// were skipped)
+    pub completed: usize,
+    /// Number of audiences that failed to prefetch
+    pub failed: usize,
+    /// Number of audiences skipped as another instance was fetching them
+    pub skipped: usize,
+}
+
+/// Prefetches the tiles of common audiences
+#[derive(Clone, Debug)]
+pub struct Warmup {
+    audiences: Arc<Vec<AudienceKey>>,
+    status: Arc<Mutex<WarmupStatus>>,
+}
+
+impl Default for Warmup {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Warmup {
+    pub fn new(audiences: Vec<AudienceKey>) -> Self {
+        let status = WarmupStatus {
+            state: if audiences.is_empty() {
+                WarmupState::Disabled
+            } else {
+                WarmupState::Pending
+            },
+            total: audiences.len(),
+            completed: 0,
+            failed: 0,
+            skipped: 0,
+        };
+        Self {
+            audiences: Arc::new(audiences),
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    /// Read the audiences to warm up from `tiles_warmup_audiences` and the
+    /// `tiles_warmup_snapshot`
+    pub fn from_settings(settings: &Settings) -> HandlerResult<Self> {
+        let mut audiences: Vec<AudienceKey> = match &settings.tiles_warmup_audiences {
+            Some(audiences) => serde_json::from_str(audiences).map_err(|e| {
+                HandlerError::internal(&format!("Invalid tiles_warmup_audiences: {:?}", e))
+            })?,
+            None => Vec::new(),
+        };
+        if let Some(path) = &settings.tiles_warmup_snapshot {
+            match read_snapshot(Path::new(path)) {
+                Ok(snapshot) => audiences.extend(snapshot),
+                // Don't prevent startup over a missing or broken snapshot
+                Err(e) => warn!("Couldn't read tiles warmup snapshot {}: {:?}", path, e),
+            }
+        }
+        let mut unique = Vec::with_capacity(audiences.len());
+        for audience_key in audiences {
+            if !unique.contains(&audience_key) {
+                unique.push(audience_key);
+            }
+        }
+        unique.truncate(settings.tiles_warmup_max);
+        Ok(Self::new(unique))
+    }
+
+    pub fn status(&self) -> WarmupStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Prefetch the audiences in the background, up to
+    /// `tiles_warmup_concurrency` at a time
+    pub fn spawn(&self, state: &ServerState) {
+        if self.audiences.is_empty() {
+            return;
+        }
+        let warmup = self.clone();
+        let state = state.clone();
+        actix_rt::spawn(async move {
+            warmup.run(&state).await;
+        });
+    }
+
+    pub async fn run(&self, state: &ServerState) {
+        self.status.lock().unwrap().state = WarmupState::Running;
+        let metrics = Metrics::from(state);
+        trace!("Warmup: prefetching {} audiences", self.audiences.len());
+        stream::iter(self.audiences.iter())
+            .for_each_concurrent(state.settings.tiles_warmup_concurrency.max(1), |key| {
+                let metrics = &metrics;
+                // Keyed as the requests of the audience are
+                let key = {
+                    let filter = state.filter.read().unwrap();
+                    state
+                        .key_dimensions
+                        .audience_key(key.clone(), |dma| filter.ignores_dma(dma))
+                };
+                async move {
+                    let result = prefetch(state, &key, metrics).await;
+                    let mut status = self.status.lock().unwrap();
+                    match result {
+                        Ok(Populated::Written) => (),
+                        Ok(Populated::Busy) => status.skipped += 1,
+                        Err(e) => {
+                            trace!("Warmup: {:?} failed: {:?}", key, e);
+                            status.failed += 1;
+                        }
+                    }
+                    status.completed += 1;
+                }
+            })
+            .await;
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.state = WarmupState::Complete;
+            status.clone()
+        };
+        trace!("Warmup: complete {:?}", status);
+        metrics.count(
+            "tiles_cache.warmup.completed",
+            (status.completed - status.failed - status.skipped) as i64,
+        );
+        metrics.count("tiles_cache.warmup.failed", status.failed as i64);
+        metrics.count("tiles_cache.warmup.skipped", status.skipped as i64);
+    }
+}
+
+/// Fetch and cache the tiles for an audience (unless a request already has)
+async fn prefetch(
+    state: &ServerState,
+    audience_key: &AudienceKey,
+    metrics: &Metrics,
+) -> HandlerResult<Populated> {
+    // Not an access: leave the entry's LRU position alone
+    if state.tiles_cache.peek(audience_key).is_some() {
+        return Ok(Populated::Written);
+    }
+    let handle = state.tiles_cache.prepare_write(audience_key, false);
+    populate(state, audience_key, handle, Duration::from_secs(0), metrics).await
+}
+
+/// The outcome of [populate]
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Populated {
+    /// The audience's tiles are in the cache
+    Written,
+    /// Skipped: another instance is fetching the audience's tiles
+    Busy,
+}
+
+/// Fetch an audience's tiles (or take another instance's that don't expire
+/// within `ahead`), writing them to the cache via `handle`
+pub(crate) async fn populate<F>(
+    state: &ServerState,
+    audience_key: &AudienceKey,
+    handle: WriteHandle<'_, F>,
+    ahead: Duration,
+    metrics: &Metrics,
+) -> HandlerResult<Populated>
+where
+    F: FnOnce(()),
+{
+    let location = audience_key.location()?;
+    let device_info = audience_key.device_info();
+    let settings = &state.settings;
+    let max_age = Duration::from_secs(settings.tiles_max_age_secs);
+    let lease_ttl = Duration::from_millis(settings.tiles_cache_lease_ms);
+    // Another instance may be fetching them too
+    let lease = match state
+        .tiles_cache
+        .shared_lookup(audience_key, max_age, ahead, lease_ttl, metrics)
+        .await
+    {
+        SharedLookup::Hit(tiles) => {
+            handle.insert(TilesState::Fresh { tiles });
+            return Ok(Populated::Written);
+        }
+        SharedLookup::Busy => return Ok(Populated::Busy),
+        SharedLookup::Fetch(lease) => lease,
+    };
+    let mut tags = Tags::default();
+    let result = providers::get_tiles(state, &location, &device_info, &mut tags, metrics, None)
+        .await
+        .and_then(|response| Tiles::new(response, add_jitter(settings)));
+    if let Ok(tiles) = &result {
+        state
+            .tiles_cache
+            .shared_set(&handle, tiles, max_age, metrics)
+            .await;
+    }
+    state.tiles_cache.shared_release(lease, metrics).await;
+    handle.insert(TilesState::Fresh { tiles: result? });
+    Ok(Populated::Written)
+}
+
+fn read_snapshot(path: &Path) -> HandlerResult<Vec<AudienceKey>> {
+    let snapshot = fs::read_to_string(path).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    serde_json::from_str(&snapshot).map_err(|e| HandlerError::internal(&e.to_string()))
+}
+
+fn write_snapshot(path: &Path, audiences: &[AudienceKey]) -> HandlerResult<()> {
+    let snapshot =
+        serde_json::to_string(audiences).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    // Write atomically, so a crash mid write doesn't lose the snapshot
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, snapshot).map_err(|e| HandlerError::internal(&e.to_string()))?;
+    fs::rename(&tmp, path).map_err(|e| HandlerError::internal(&e.to_string()))
+}
+
+/// Periodically snapshot the audiences in the cache to `tiles_warmup_snapshot`
+pub fn spawn_snapshotter(cache: &TilesCache, settings: &Settings) {
+    let path = match &settings.tiles_warmup_snapshot {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let cache = cache.clone();
+    let interval = Duration::from_secs(settings.tiles_warmup_snapshot_interval_secs);
+    let max = settings.tiles_warmup_max;
+    actix_rt::spawn(async move {
+        loop {
+            actix_rt::time::delay_for(interval).await;
+            let mut audiences = cache.audience_keys();
+            if audiences.is_empty() {
+                continue;
+            }
+            audiences.truncate(max);
+            if let Err(e) = write_snapshot(Path::new(&path), &audiences) {
+                warn!("Couldn't write tiles warmup snapshot {}: {:?}", path, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::{FormFactor, OsFamily};
+
+    #[test]
+    fn audiences_from_settings() {
+        let snapshot =
+            std::env::temp_dir().join(format!("contile-warmup-{}.json", std::process::id()));
+        let wa = AudienceKey {
+            country_code: "US".to_owned(),
+            region_code: Some("WA".to_owned()),
+            dma_code: None,
+            form_factor: FormFactor::Desktop,
+            os_family: OsFamily::Windows,
+            legacy_only: false,
+        };
+        let ca = AudienceKey {
+            region_code: Some("CA".to_owned()),
+            ..wa.clone()
+        };
+        write_snapshot(&snapshot, &[ca.clone(), wa.clone()]).unwrap();
+
+        let settings = Settings {
+            tiles_warmup_audiences: Some(
+                r#"[{"country_code": "US", "region_code": "WA",
+                     "form_factor": "desktop", "os_family": "windows"}]"#
+                    .to_owned(),
+            ),
+            tiles_warmup_snapshot: Some(snapshot.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let warmup = Warmup::from_settings(&settings).unwrap();
+        // Deduplicated, the configured audiences first
+        assert_eq!(*warmup.audiences, vec![wa.clone(), ca]);
+        assert_eq!(warmup.status().state, WarmupState::Pending);
+        assert_eq!(warmup.status().total, 2);
+
+        let warmup = Warmup::from_settings(&Settings {
+            tiles_warmup_max: 1,
+            ..settings.clone()
+        })
+        .unwrap();
+        assert_eq!(*warmup.audiences, vec![wa]);
+
+        std::fs::remove_file(&snapshot).unwrap();
+        // A missing snapshot is skipped
+        assert_eq!(Warmup::from_settings(&settings).unwrap().status().total, 1);
+        assert!(Warmup::from_settings(&Settings {
+            tiles_warmup_audiences: Some("[{}]".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+        assert_eq!(
+            Warmup::from_settings(&Settings::default())
+                .unwrap()
+                .status()
+                .state,
+            WarmupState::Disabled
+        );
+    }
+}
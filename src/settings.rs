@@ -8,6 +8,7 @@ use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 
 use crate::adm::AdmFilterSettings;
+use crate::providers::PartnerSettings;
 use crate::server::{img_storage::StorageSettings, ServerState};
 
 static PREFIX: &str = "contile";
@@ -18,6 +19,10 @@ static DEFAULT_PORT: u16 = 8000;
 pub enum TestModes {
     TestTimeout,
     TestFakeResponse,
+    /// Record the partner responses to `test_recording_path`
+    Record,
+    /// Replay the partner responses recorded in `test_recording_path`
+    Replay,
     NoTest,
 }
 
@@ -29,12 +34,28 @@ impl std::fmt::Display for TestModes {
             match self {
                 Self::TestTimeout => "Test Timeout",
                 Self::TestFakeResponse => "Test Fake Response",
+                Self::Record => "Record",
+                Self::Replay => "Replay",
                 Self::NoTest => "No Test",
             }
         )
     }
 }
 
+/// Which tile position wins when both the advertiser settings
+/// ([crate::adm::AdmAdvertiserFilterSettings]) and the partner specify one
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PositionPrecedence {
+    Settings,
+    Partner,
+}
+
+impl Default for PositionPrecedence {
+    fn default() -> Self {
+        Self::Settings
+    }
+}
+
 // TODO: Call this `EnvSettings` that serializes into
 // real `Settings`?
 //
@@ -66,6 +87,66 @@ pub struct Settings {
     pub actix_keep_alive: Option<u64>,
     /// Expire tiles after this many seconds (15 * 60s)
     pub tiles_ttl: u32,
+    /// How long (in milliseconds) a request may wait on another request
+    /// that's already fetching the same uncached tiles before giving up with
+    /// a 204 (default: 2000)
+    pub tiles_populating_wait_ms: u64,
+    /// Continue serving expired tiles for up to this many seconds past their
+    /// expiry when refreshing them fails (default: 60 * 60s)
+    pub tiles_stale_if_error_secs: u64,
+    /// Drop tiles this many seconds after they were fetched, regardless of
+    /// their state (default: 2 * 60 * 60s)
+    pub tiles_max_age_secs: u64,
+    /// Drop tiles this many seconds past their expiry, when they're no longer
+    /// likely to be served (default: 60 * 60s)
+    pub tiles_cache_gc_grace_secs: u64,
+    /// Evict the least recently used tiles when the cache holds more than
+    /// this many entries (default: 0, unbounded)
+    pub tiles_cache_max_entries: usize,
+    /// Evict the least recently used tiles when the cache holds more than
+    /// this many bytes of tiles (default: 0, unbounded)
+    pub tiles_cache_max_bytes: usize,
+    /// Path to snapshot the tiles cache to on shutdown, reloaded (minus the
+    /// expired tiles) on startup (default: none)
+    pub tiles_cache_snapshot: Option<String>,
+    /// Give up reloading `tiles_cache_snapshot` after this many milliseconds
+    /// (default: 2000)
+    pub tiles_cache_snapshot_load_ms: u64,
+    /// Refresh the tiles of recently accessed audiences in the background
+    /// this many seconds before they expire (see
+    /// [crate::server::refresher]) (default: 0, disabled)
+    pub tiles_refresh_ahead_secs: u64,
+    /// Only refresh the tiles of audiences accessed within this many seconds
+    /// (default: 300)
+    pub tiles_refresh_accessed_secs: u64,
+    /// Milliseconds between checks for tiles to refresh (default: 10000)
+    pub tiles_refresh_interval_ms: u64,
+    /// Maximum number of tiles refreshed concurrently (default: 4)
+    pub tiles_refresh_concurrency: usize,
+    /// Share tiles with other instances via this backend: a
+    /// `redis://[:password@]host[:port][/db]` URL (default: none)
+    pub tiles_cache_backend: Option<String>,
+    /// A JSON map of countries to the optional dimensions ("region", "dma")
+    /// of their tiles cache key, "*" for the other countries (see
+    /// [crate::server::cache::KeyDimensions]) (default: every dimension)
+    pub tiles_cache_key_dimensions: Option<String>,
+    /// How long (in milliseconds) an instance may hold the lease to refresh
+    /// an audience's shared tiles (default: 10000)
+    pub tiles_cache_lease_ms: u64,
+    /// Timeout (in milliseconds) for requests to `tiles_cache_backend`
+    /// (default: 500)
+    pub tiles_cache_backend_timeout_ms: u64,
+    /// A JSON list of audiences whose tiles are prefetched at startup (see
+    /// [crate::server::warmup])
+    pub tiles_warmup_audiences: Option<String>,
+    /// Path of a snapshot of recent audiences, also prefetched at startup
+    pub tiles_warmup_snapshot: Option<String>,
+    /// Seconds between rewrites of `tiles_warmup_snapshot` (default: 300)
+    pub tiles_warmup_snapshot_interval_secs: u64,
+    /// Maximum number of audiences prefetched at startup (default: 500)
+    pub tiles_warmup_max: usize,
+    /// Maximum number of concurrent warmup prefetches (default: 4)
+    pub tiles_warmup_concurrency: usize,
     /// path to MaxMind location database
     pub maxminddb_loc: Option<PathBuf>,
     /// A JSON formatted string of [StorageSettings] related to
@@ -75,6 +156,9 @@ pub struct Settings {
     pub test_mode: TestModes,
     /// path to the test files
     pub test_file_path: String,
+    /// path to the partner response recordings (`Record`/`Replay`
+    /// `test_mode`s)
+    pub test_recording_path: String,
     /// Location test header override
     pub location_test_header: Option<String>,
     /// Fallback country (if no country is able to be determined for an
@@ -83,6 +167,9 @@ pub struct Settings {
     pub fallback_country: String,
     /// URL to the official documentation
     pub documentation_url: String,
+    /// Bearer token authorizing requests to the admin API (see
+    /// [crate::web::admin]), which is disabled when unset (default: none)
+    pub admin_token: Option<String>,
     /// Operational trace header
     pub trace_header: Option<String>,
     /// a JSON list of location DMAs to never return (population less than 15K)
@@ -109,8 +196,20 @@ pub struct Settings {
     pub adm_mobile_endpoint_url: Option<String>,
     /// max number of tiles returned to clients (default: 2)
     pub adm_max_tiles: u8,
+    /// Whether tile positions from the ADM settings ("Settings") or from the
+    /// partner ("Partner") take precedence (default: Settings)
+    pub position_precedence: PositionPrecedence,
     /// number of tiles to query from ADM (default: 10)
     pub adm_query_tile_count: u8,
+    /// The query parameters sent to ADM, as a JSON list (see
+    /// [crate::adm::QueryTemplate]) (default: ADM's v1.0 parameters)
+    pub adm_query_template: Option<String>,
+    /// Mobile version of the above (default: `adm_query_template`)
+    pub adm_mobile_query_template: Option<String>,
+    /// An ordered JSON list of rules selecting the ADM partner_id/sub1/endpoint
+    /// by form factor, OS, country and Firefox version (see
+    /// [crate::adm::PseRule])
+    pub adm_partner_rules: Option<String>,
     /// Timeout requests to the ADM server after this many seconds (default: 5)
     pub adm_timeout: u64,
     /// ADM tile settings (either as JSON, a path to a JSON file, or a Google Storage url)
@@ -123,12 +222,52 @@ pub struct Settings {
     pub adm_refresh_rate_secs: u64,
     /// Check ADM settings on new tile requests.
     pub adm_live_update: bool,
+    /// Minimum number of seconds between `adm_live_update` checks
+    pub adm_live_update_interval_secs: u64,
     /// A JSON list of advertisers to ignore, specified by the Advertiser name.
     pub adm_ignore_advertisers: Option<String>,
     /// a JSON list of advertisers to allow for versions of firefox less than 91.
     pub adm_has_legacy_image: Option<String>,
     /// Percentage of overall time for fetch "jitter".
     pub jitter: u8,
+    /// A JSON list of [crate::providers::PartnerSettings] to concurrently
+    /// fetch tiles from. When not specified, a single "adm" partner is built
+    /// from the `adm_*` settings above.
+    pub partners: Option<String>,
+    /// Maximum number of times to retry a failed partner request (default: 1)
+    pub partner_max_retries: u8,
+    /// Base delay (in milliseconds) between partner request retries. Doubled
+    /// on each attempt, with full jitter applied (default: 50)
+    pub partner_retry_backoff_ms: u64,
+    /// Open a partner's circuit breaker after this many failures within
+    /// `partner_breaker_window_secs`. 0 disables the breaker (default: 5)
+    pub partner_breaker_failures: u32,
+    /// Window (in seconds) in which partner failures are counted (default: 60)
+    pub partner_breaker_window_secs: u64,
+    /// How long (in seconds) an open circuit breaker fails fast before
+    /// probing the partner again (default: 30)
+    pub partner_breaker_open_secs: u64,
+    /// Hedge slow partner requests: send a second, identical request when
+    /// the first hasn't answered within `partner_hedge_percentile` of the
+    /// partner endpoint's recent latencies (default: false)
+    pub partner_hedge: bool,
+    /// Percentile (0-100) of the endpoint's recent latencies after which a
+    /// request is hedged (default: 95)
+    pub partner_hedge_percentile: f64,
+    /// Minimum delay (in milliseconds) before hedging a request (default: 50)
+    pub partner_hedge_min_delay_ms: u64,
+    /// Maximum fraction (0-1) of all partner requests that may be hedged
+    /// (default: 0.05)
+    pub partner_hedge_max_rate: f64,
+    /// Maximum rate (requests per second) of outbound requests to each
+    /// partner endpoint. Requests over the limit are served stale or empty
+    /// tiles. 0 disables the limit; set it (e.g.
+    /// `CONTILE_PARTNER_RATE_LIMIT=50`) to the rate the partner agreed to
+    /// (default: 0)
+    pub partner_rate_limit: f64,
+    /// Number of requests to each partner endpoint that may be sent in a
+    /// burst above `partner_rate_limit` (default: 100)
+    pub partner_rate_limit_burst: u32,
 }
 
 impl Default for Settings {
@@ -144,13 +283,36 @@ impl Default for Settings {
             statsd_port: 8125,
             actix_keep_alive: None,
             tiles_ttl: 15 * 60,
+            tiles_populating_wait_ms: 2000,
+            tiles_stale_if_error_secs: 60 * 60,
+            tiles_max_age_secs: 2 * 60 * 60,
+            tiles_cache_gc_grace_secs: 60 * 60,
+            tiles_cache_max_entries: 0,
+            tiles_cache_max_bytes: 0,
+            tiles_cache_snapshot: None,
+            tiles_cache_snapshot_load_ms: 2000,
+            tiles_refresh_ahead_secs: 0,
+            tiles_refresh_accessed_secs: 300,
+            tiles_refresh_interval_ms: 10_000,
+            tiles_refresh_concurrency: 4,
+            tiles_cache_backend: None,
+            tiles_cache_key_dimensions: None,
+            tiles_cache_lease_ms: 10_000,
+            tiles_cache_backend_timeout_ms: 500,
+            tiles_warmup_audiences: None,
+            tiles_warmup_snapshot: None,
+            tiles_warmup_snapshot_interval_secs: 5 * 60,
+            tiles_warmup_max: 500,
+            tiles_warmup_concurrency: 4,
             maxminddb_loc: None,
             storage: "".to_owned(),
             test_mode: TestModes::NoTest,
             test_file_path: "./tools/test/test_data/".to_owned(),
+            test_recording_path: "./tools/test/recordings/".to_owned(),
             location_test_header: None,
             fallback_country: "US".to_owned(),
             documentation_url: "https://developer.mozilla.org/".to_owned(),
+            admin_token: None,
             trace_header: Some("X-Cloud-Trace-Context".to_owned()),
             // exclude for: Glendive, MT(798); Alpena, MI(583); North Platte, NE (740)
             exclude_dma: Some("[798, 583, 740]".to_owned()),
@@ -165,26 +327,44 @@ impl Default for Settings {
             adm_mobile_partner_id: None,
             adm_mobile_sub1: None,
             adm_max_tiles: 2,
+            position_precedence: PositionPrecedence::Settings,
             adm_query_tile_count: 10,
+            adm_query_template: None,
+            adm_mobile_query_template: None,
+            adm_partner_rules: None,
             adm_timeout: 5,
             adm_settings: "".to_owned(),
             adm_refresh_rate_secs: 300,
             adm_live_update: false,
+            adm_live_update_interval_secs: 5,
             adm_ignore_advertisers: None,
             adm_has_legacy_image: Some(
                 r#"["adidas","amazon","ebay","etsy","geico","nike","samsung","wix"]"#.to_owned(),
             ),
             // +/- 10% of time for jitter.
             jitter: 10,
+            partners: None,
+            partner_max_retries: 1,
+            partner_retry_backoff_ms: 50,
+            partner_breaker_failures: 5,
+            partner_breaker_window_secs: 60,
+            partner_breaker_open_secs: 30,
+            partner_hedge: false,
+            partner_hedge_percentile: 95.0,
+            partner_hedge_min_delay_ms: 50,
+            partner_hedge_max_rate: 0.05,
+            partner_rate_limit: 0.0,
+            partner_rate_limit_burst: 100,
         }
     }
 }
 
 impl Settings {
     pub fn verify_settings(&mut self) -> Result<(), ConfigError> {
-        if self.adm_endpoint_url.is_empty() {
+        if self.partners.is_none() && self.adm_endpoint_url.is_empty() {
             return Err(ConfigError::Message("Missing adm_endpoint_url".to_owned()));
         }
+        PartnerSettings::list_from_settings(self)?;
 
         if self.fallback_country.len() != 2 {
             return Err(ConfigError::Message(
@@ -222,7 +402,8 @@ impl Settings {
         Ok(match s.try_into::<Self>() {
             Ok(mut s) => {
                 trace!("raw Settings: {:?}", &s);
-                if debug || s.test_mode != TestModes::NoTest {
+                // Recording requires talking to the real partner
+                if debug || !matches!(s.test_mode, TestModes::NoTest | TestModes::Record) {
                     trace!("!! Running in test mode!");
                     s.adm_endpoint_url = "http://localhost:8675/".to_owned();
                     s.debug = true;
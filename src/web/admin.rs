@@ -0,0 +1,114 @@
+//! Admin API for inspecting and purging the tiles cache
+//!
+//! Disabled (404) unless `admin_token` is set, requests must then include it
+//! as an `Authorization: Bearer <admin_token>` header.
+//!
+//! * `GET /__admin__/cache` - list the cache entries
+//! * `GET /__admin__/cache/entry?country_code=US&form_factor=desktop&...` -
+//!   an entry's tiles (the query being its [AudienceKey])
+//! * `POST /__admin__/cache/purge?country_code=US&advertiser=Example` -
+//!   remove the entries matching all the given `country_code`, `os_family`,
+//!   `form_factor` and `advertiser` (name of an advertiser in their tiles)
+//!
+//! These only apply to the instance serving the request: a purge matches
+//! its entries (removing them from the shared backend too, if configured),
+//! other instances keep theirs until they expire.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::{HandlerErrorKind, HandlerResult},
+    metrics::Metrics,
+    server::{
+        cache::{AudienceKey, TilesContent},
+        ServerState,
+    },
+    web::{FormFactor, OsFamily},
+};
+
+/// Proof of a request's authorization to the admin API (see
+/// [crate::web::extractors])
+pub struct AdminAuth;
+
+/// Which entries to purge: those matching all of the given fields
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    pub country_code: Option<String>,
+    pub os_family: Option<OsFamily>,
+    pub form_factor: Option<FormFactor>,
+    pub advertiser: Option<String>,
+}
+
+/// Handles the admin endpoints
+pub fn service(config: &mut web::ServiceConfig) {
+    config
+        .service(web::resource("/cache").route(web::get().to(list)))
+        .service(web::resource("/cache/entry").route(web::get().to(entry)))
+        .service(web::resource("/cache/purge").route(web::post().to(purge)));
+}
+
+async fn list(_: AdminAuth, state: web::Data<ServerState>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "entries": state.tiles_cache.entries() }))
+}
+
+async fn entry(
+    _: AdminAuth,
+    audience_key: web::Query<AudienceKey>,
+    state: web::Data<ServerState>,
+) -> HandlerResult<HttpResponse> {
+    // Inspecting an entry shouldn't save it from eviction
+    let tiles_state = match state.tiles_cache.peek(&audience_key) {
+        Some(tiles_state) => tiles_state,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let content = match tiles_state.tiles().map(|tiles| &tiles.content) {
+        Some(TilesContent::Json(payload)) => serde_json::from_str(payload.json())
+            .map_err(|e| HandlerErrorKind::Internal(format!("Invalid cached tiles: {:?}", e)))?,
+        _ => Value::Null,
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "audience_key": &*audience_key,
+        "state": tiles_state.name(),
+        "content": content,
+    })))
+}
+
+async fn purge(
+    _: AdminAuth,
+    query: web::Query<PurgeQuery>,
+    state: web::Data<ServerState>,
+    metrics: Metrics,
+) -> HandlerResult<HttpResponse> {
+    let PurgeQuery {
+        country_code,
+        os_family,
+        form_factor,
+        advertiser,
+    } = query.into_inner();
+    if country_code.is_none()
+        && os_family.is_none()
+        && form_factor.is_none()
+        && advertiser.is_none()
+    {
+        // Purging everything is likely a mistake
+        return Err(HandlerErrorKind::Validation(
+            "At least one of country_code, os_family, form_factor or advertiser is required"
+                .to_owned(),
+        )
+        .into());
+    }
+    let purged = state.tiles_cache.purge(|audience_key, tiles| {
+        country_code.as_ref().map_or(true, |country_code| {
+            audience_key.country_code.eq_ignore_ascii_case(country_code)
+        }) && os_family.map_or(true, |os_family| audience_key.os_family == os_family)
+            && form_factor.map_or(true, |form_factor| audience_key.form_factor == form_factor)
+            && advertiser
+                .as_ref()
+                .map_or(true, |advertiser| tiles.has_advertiser(advertiser))
+    });
+    state.tiles_cache.shared_delete(&purged, &metrics).await;
+    info!("Admin: purged {} tiles cache entries", purged.len());
+    metrics.count("admin.cache.purged", purged.len() as i64);
+    Ok(HttpResponse::Ok().json(json!({ "purged": purged.len() })))
+}
@@ -12,7 +12,10 @@ use actix_web_location::Location;
 use serde::Deserialize;
 use serde_json::{json, Value};
 
-use crate::{error::HandlerError, server::ServerState};
+use crate::{
+    error::HandlerError,
+    server::{warmup::WarmupState, ServerState},
+};
 
 /// Well Known DockerFlow commands for Ops callbacks
 pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
@@ -62,6 +65,25 @@ fn heartbeat(state: web::Data<ServerState>) -> HttpResponse {
             Value::String(state.settings.test_mode.to_string()),
         );
     }
+    let partners: HashMap<_, _> = state
+        .providers
+        .iter()
+        .filter_map(|provider| {
+            provider.breaker().map(|breaker| {
+                (
+                    provider.name(),
+                    json!({"breaker": breaker.state().to_string()}),
+                )
+            })
+        })
+        .collect();
+    if !partners.is_empty() {
+        checklist.insert("partners".to_owned(), json!(partners));
+    }
+    let warmup = state.warmup.status();
+    if warmup.state != WarmupState::Disabled {
+        checklist.insert("warmup".to_owned(), json!(warmup));
+    }
     HttpResponse::Ok().json(checklist)
 }
 
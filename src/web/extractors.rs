@@ -5,15 +5,21 @@
 
 use actix_web::{
     dev::Payload,
+    error::ErrorNotFound,
     http::{header, HeaderValue},
-    Error, FromRequest, HttpRequest,
+    web, Error, FromRequest, HttpRequest,
 };
 use futures::future::{self, FutureExt, LocalBoxFuture};
 use lazy_static::lazy_static;
 
 use crate::{
+    error::HandlerErrorKind,
     metrics::Metrics,
-    web::user_agent::{get_device_info, DeviceInfo},
+    server::ServerState,
+    web::{
+        admin::AdminAuth,
+        user_agent::{get_device_info, DeviceInfo},
+    },
 };
 
 lazy_static! {
@@ -49,3 +55,42 @@ impl FromRequest for Metrics {
         future::ok(Metrics::from(req)).boxed_local()
     }
 }
+
+impl FromRequest for AdminAuth {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let admin_token = req
+            .app_data::<web::Data<ServerState>>()
+            .and_then(|state| state.settings.admin_token.clone())
+            .filter(|admin_token| !admin_token.is_empty());
+        let result = match admin_token {
+            // The admin API is disabled
+            None => Err(ErrorNotFound("Not Found")),
+            Some(admin_token) => {
+                let authorized = req
+                    .headers()
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map_or(false, |token| {
+                        constant_time_eq(token.trim().as_bytes(), admin_token.as_bytes())
+                    });
+                if authorized {
+                    Ok(AdminAuth)
+                } else {
+                    Err(HandlerErrorKind::Unauthorized.into())
+                }
+            }
+        };
+        future::ready(result).boxed_local()
+    }
+}
+
+/// Compare without short circuiting, so the time taken doesn't reveal how
+/// much of a token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
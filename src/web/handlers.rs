@@ -1,5 +1,7 @@
 //! API Handlers
-use actix_web::{web, HttpRequest, HttpResponse};
+use std::time::Duration;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
 use actix_web_location::Location;
 use lazy_static::lazy_static;
 use rand::{thread_rng, Rng};
@@ -8,11 +10,12 @@ use crate::{
     adm,
     error::{HandlerErrorKind, HandlerResult},
     metrics::Metrics,
+    providers,
     server::{
-        cache::{self, Tiles, TilesState},
+        cache::{self, Encoding, SharedLookup, Tiles, TilesState},
         ServerState,
     },
-    settings::Settings,
+    settings::{Settings, TestModes},
     tags::Tags,
     web::{middleware::sentry as l_sentry, DeviceInfo},
 };
@@ -36,7 +39,8 @@ pub fn add_jitter(settings: &Settings) -> u32 {
 /// Handler for `.../v1/tiles` endpoint
 ///
 /// Normalizes User Agent info and searches cache for possible tile suggestions.
-/// On a miss, it will attempt to fetch new tiles from ADM.
+/// On a miss, it will attempt to fetch new tiles from the configured
+/// [providers::TileProvider]s.
 pub async fn get_tiles(
     location: Location,
     device_info: DeviceInfo,
@@ -48,6 +52,9 @@ pub async fn get_tiles(
     metrics.incr("tiles.get");
 
     let settings = &state.settings;
+    if let Some(live_updater) = &state.live_updater {
+        live_updater.check(&metrics);
+    }
     if !state
         .filter
         .read()
@@ -69,18 +76,14 @@ pub async fn get_tiles(
         return Ok(response);
     }
 
-    let audience_key = cache::AudienceKey {
-        country_code: location.country(),
-        region_code: if location.region() != "" {
-            Some(location.region())
-        } else {
-            None
-        },
-        dma_code: location.dma,
-        form_factor: device_info.form_factor,
-        os_family: device_info.os_family,
-        legacy_only: device_info.legacy_only(),
+    // Only the dimensions of the cache key are passed on to the partner
+    let location = {
+        let filter = state.filter.read().unwrap();
+        state
+            .key_dimensions
+            .location(location, |dma| filter.ignores_dma(dma))
     };
+    let audience_key = cache::AudienceKey::new(&location, &device_info);
 
     let mut tags = Tags::default();
     {
@@ -89,29 +92,38 @@ pub async fn get_tiles(
         // tags.clone().commit(&mut request.extensions_mut());
     }
 
+    let max_age = Duration::from_secs(settings.tiles_max_age_secs);
     let mut expired = false;
-    if settings.test_mode != crate::settings::TestModes::TestFakeResponse {
+    let mut stale = None;
+    // Bypass the cache when serving test responses
+    let bypass_cache = matches!(
+        settings.test_mode,
+        TestModes::TestFakeResponse | TestModes::Replay
+    );
+    if !bypass_cache {
+        let mut inflight = None;
         // First make a cheap read from the cache
         if let Some(tiles_state) = state.tiles_cache.get(&audience_key) {
             match &*tiles_state {
-                TilesState::Populating => {
+                TilesState::Populating { inflight: fetch } => {
                     // Another task is currently populating this entry and will
-                    // complete shortly. 204 until then instead of queueing
-                    // more redundant requests
+                    // complete shortly. Share its result instead of queueing
+                    // more redundant requests (awaited below, after releasing
+                    // the cache entry)
                     trace!("get_tiles: Another task Populating");
-                    metrics.incr("tiles_cache.miss.populating");
-                    return Ok(HttpResponse::NoContent().finish());
+                    inflight = Some(fetch.clone());
                 }
-                TilesState::Fresh { tiles } => {
+                TilesState::Fresh { tiles } if !tiles.too_old(max_age) => {
                     expired = tiles.expired();
                     if !expired {
                         trace!("get_tiles: cache hit: {:?}", audience_key);
                         metrics.incr("tiles_cache.hit");
-                        return Ok(content_response(&tiles.content));
+                        return Ok(content_response(tiles, &request));
                     }
                     // Needs refreshing
+                    stale = Some(tiles.clone());
                 }
-                TilesState::Refreshing { tiles } => {
+                TilesState::Refreshing { tiles } if !tiles.too_old(max_age) => {
                     // Another task is currently refreshing this entry, just
                     // return the stale Tiles until it's completed
                     trace!(
@@ -119,10 +131,19 @@ pub async fn get_tiles(
                         audience_key
                     );
                     metrics.incr("tiles_cache.hit.refreshing");
-                    return Ok(content_response(&tiles.content));
+                    return Ok(content_response(tiles, &request));
+                }
+                TilesState::Fresh { .. } | TilesState::Refreshing { .. } => {
+                    // Past the hard maximum age: drop the entry (it's
+                    // replaced when we populate it below)
+                    trace!("get_tiles: dropping tiles past max age: {:?}", audience_key);
+                    metrics.incr("tiles_cache.max_age.dropped");
                 }
             }
         }
+        if let Some(inflight) = inflight {
+            return Ok(await_populating(inflight, settings, &metrics, &request).await);
+        }
     }
 
     // Alter the cache separately from the read above: writes are more
@@ -130,29 +151,88 @@ pub async fn get_tiles(
 
     // Prepare to write: temporarily set the cache entry to
     // Refreshing/Populating until we've completed our write, notifying other
-    // requests in flight during this time to return stale data/204 No Content
-    // instead of making duplicate/redundant writes. The handle will reset the
+    // requests in flight during this time to return stale data/await our
+    // result instead of making duplicate/redundant writes. The handle will reset the
     // temporary state if no write occurs (due to errors/panics)
     let handle = state.tiles_cache.prepare_write(&audience_key, expired);
 
-    let result = adm::get_tiles(
+    // Another instance may have already fetched these tiles (or be fetching
+    // them)
+    let lease = if bypass_cache {
+        None
+    } else {
+        match state
+            .tiles_cache
+            .shared_lookup(
+                &audience_key,
+                max_age,
+                Duration::from_secs(0),
+                Duration::from_millis(settings.tiles_cache_lease_ms),
+                &metrics,
+            )
+            .await
+        {
+            SharedLookup::Hit(tiles) => {
+                trace!("get_tiles: shared cache hit: {:?}", &audience_key);
+                metrics.incr("tiles_cache.shared.hit");
+                handle.insert(TilesState::Fresh {
+                    tiles: tiles.clone(),
+                });
+                return Ok(content_response(&tiles, &request));
+            }
+            SharedLookup::Busy => {
+                trace!("get_tiles: shared cache busy: {:?}", &audience_key);
+                metrics.incr("tiles_cache.shared.busy");
+                // Serve our expired tiles meanwhile, otherwise await the
+                // other instance's
+                let tiles = match stale {
+                    Some(tiles) => Some(tiles),
+                    None => {
+                        state
+                            .tiles_cache
+                            .shared_await(
+                                &audience_key,
+                                max_age,
+                                Duration::from_millis(settings.tiles_populating_wait_ms),
+                                &metrics,
+                            )
+                            .await
+                    }
+                };
+                return Ok(match tiles {
+                    Some(tiles) => content_response(&tiles, &request),
+                    None => HttpResponse::NoContent().finish(),
+                });
+            }
+            SharedLookup::Fetch(lease) => lease,
+        }
+    };
+
+    let result = providers::get_tiles(
         &state,
         &location,
-        device_info,
+        &device_info,
         &mut tags,
         &metrics,
         // be aggressive about not passing headers unless we absolutely need to
-        if settings.test_mode != crate::settings::TestModes::NoTest {
+        if settings.test_mode != TestModes::NoTest {
             Some(request.head().headers())
         } else {
             None
         },
     )
-    .await;
+    .await
+    .and_then(|response| cache::Tiles::new(response, add_jitter(&state.settings)));
+    if let Ok(tiles) = &result {
+        state
+            .tiles_cache
+            .shared_set(&handle, tiles, max_age, &metrics)
+            .await;
+    }
+    state.tiles_cache.shared_release(lease, &metrics).await;
 
     match result {
-        Ok(response) => {
-            let tiles = cache::Tiles::new(response, add_jitter(&state.settings))?;
+        Ok(tiles) => {
             trace!(
                 "get_tiles: cache miss{}: {:?}",
                 if expired { " (expired)" } else { "" },
@@ -162,18 +242,25 @@ pub async fn get_tiles(
             handle.insert(TilesState::Fresh {
                 tiles: tiles.clone(),
             });
-            Ok(content_response(&tiles.content))
+            Ok(content_response(&tiles, &request))
         }
         Err(e) => {
+            // Serve the last known good tiles instead, if they're recent
+            // enough
+            let stale = stale.filter(|tiles| {
+                tiles.stale_if_error(Duration::from_secs(settings.tiles_stale_if_error_secs))
+            });
             // Add some kind of stats to Retrieving or RetrievingFirst?
             // do we need a kill switch if we're restricting like this already?
             match e.kind() {
                 HandlerErrorKind::BadAdmResponse(es) => {
                     warn!("Bad response from ADM: {:?}", e);
                     metrics.incr_with_tags("tiles.invalid", Some(&tags));
-                    handle.insert(TilesState::Fresh {
-                        tiles: Tiles::empty(add_jitter(&state.settings)),
-                    });
+                    if stale.is_none() {
+                        handle.insert(TilesState::Fresh {
+                            tiles: Tiles::empty(add_jitter(&state.settings)),
+                        });
+                    }
                     // Report directly to sentry
                     // (This is starting to become a pattern. 🤔)
                     let mut tags = Tags::from_head(request.head(), settings);
@@ -181,19 +268,169 @@ pub async fn get_tiles(
                     tags.add_tag("level", "warning");
                     l_sentry::report(sentry::event_from_error(&e), &tags);
                     warn!("ADM Server error: {:?}", e);
-                    Ok(HttpResponse::NoContent().finish())
                 }
-                _ => Err(e),
+                HandlerErrorKind::PartnerUnavailable(_) => {
+                    // The partner's circuit breaker is open: fail fast
+                    trace!("get_tiles: partner unavailable: {:?}", &audience_key);
+                    metrics.incr_with_tags("tiles.partner_unavailable", Some(&tags));
+                }
+                HandlerErrorKind::PartnerRateLimited(_) => {
+                    // Over the partner's outbound rate limit
+                    trace!("get_tiles: partner rate limited: {:?}", &audience_key);
+                    metrics.incr_with_tags("tiles.partner_rate_limited", Some(&tags));
+                }
+                _ => {
+                    if stale.is_none() {
+                        return Err(e);
+                    }
+                    // Still report the error we're masking
+                    if e.kind().is_sentry_event() {
+                        let mut tags = tags.clone();
+                        tags.extend(e.tags.clone());
+                        l_sentry::report(sentry::event_from_error(&e), &tags);
+                    }
+                }
             }
+            Ok(match stale {
+                Some(tiles) => {
+                    trace!("get_tiles: serving stale tiles: {:?}", &audience_key);
+                    metrics.incr_with_tags("tiles_cache.stale_if_error", Some(&tags));
+                    content_response(&tiles, &request)
+                }
+                None => HttpResponse::NoContent().finish(),
+            })
         }
     }
 }
 
-fn content_response(content: &cache::TilesContent) -> HttpResponse {
-    match content {
-        cache::TilesContent::Json(json) => HttpResponse::Ok()
-            .content_type("application/json")
-            .body(json),
-        cache::TilesContent::Empty => HttpResponse::NoContent().finish(),
+/// Wait (up to `tiles_populating_wait_ms`) for the task populating a cache
+/// entry, sharing its result. 204 if it doesn't complete in time.
+async fn await_populating(
+    inflight: cache::Inflight,
+    settings: &Settings,
+    metrics: &Metrics,
+    request: &HttpRequest,
+) -> HttpResponse {
+    let budget = Duration::from_millis(settings.tiles_populating_wait_ms);
+    match actix_rt::time::timeout(budget, inflight).await {
+        Ok(Ok(tiles)) => {
+            trace!("get_tiles: shared Populating result");
+            metrics.incr("tiles_cache.miss.coalesced");
+            content_response(&tiles, request)
+        }
+        Ok(Err(_)) => {
+            // The populating task failed
+            metrics.incr("tiles_cache.miss.populating");
+            HttpResponse::NoContent().finish()
+        }
+        Err(_) => {
+            trace!("get_tiles: timed out awaiting Populating");
+            metrics.incr("tiles_cache.miss.populating");
+            HttpResponse::NoContent().finish()
+        }
+    }
+}
+
+/// Respond with the tiles, cacheable by the client until they expire.
+///
+/// The tiles are sent in the client's preferred of their precompressed
+/// encodings. `304 Not Modified` when the client's copy (per `If-None-Match`)
+/// is current.
+fn content_response(tiles: &Tiles, request: &HttpRequest) -> HttpResponse {
+    let cache_control = format!("private, max-age={}", tiles.remaining().as_secs());
+    let payload = match &tiles.content {
+        cache::TilesContent::Json(payload) => payload,
+        cache::TilesContent::Empty => {
+            return HttpResponse::NoContent()
+                .header(header::CACHE_CONTROL, cache_control)
+                .finish()
+        }
+    };
+    let (encoding, body) = negotiate_encoding(request, payload);
+    // Each encoding's a distinct representation, needing its own (strong)
+    // validator
+    let etag = tiles.etag().map(|etag| match encoding.token() {
+        Some(token) => format!("{}-{}\"", etag.trim_end_matches('"'), token),
+        None => etag.to_owned(),
+    });
+    let not_modified = etag
+        .as_deref()
+        .map_or(false, |etag| if_none_match(request, etag));
+    let mut builder = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::VARY, "Accept-Encoding");
+    if let Some(etag) = etag {
+        builder.header(header::ETAG, etag);
     }
+    if not_modified {
+        return builder.finish();
+    }
+    if let Some(token) = encoding.token() {
+        builder.header(header::CONTENT_ENCODING, token);
+    }
+    builder.content_type("application/json").body(body.clone())
+}
+
+/// Choose the most acceptable (per `Accept-Encoding`) of the payload's
+/// compressed encodings, preferring brotli. Identity when none are accepted
+fn negotiate_encoding<'a>(
+    request: &HttpRequest,
+    payload: &'a cache::JsonPayload,
+) -> (Encoding, &'a web::Bytes) {
+    let accepted: Vec<(String, f32)> = request
+        .headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_lowercase();
+            let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(quality) => quality.trim().parse().ok()?,
+                None => 1.0,
+            };
+            Some((name, quality))
+        })
+        .collect();
+    let quality = |token: &str| {
+        accepted
+            .iter()
+            .find(|(name, _)| name == token)
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+    };
+    let mut best = None;
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+        let (quality, body) = match (
+            encoding.token().and_then(quality),
+            payload.encoded(encoding),
+        ) {
+            (Some(quality), Some(body)) if quality > 0.0 => (quality, body),
+            _ => continue,
+        };
+        if best.map_or(true, |(best, _, _)| quality > best) {
+            best = Some((quality, encoding, body));
+        }
+    }
+    best.map_or(
+        (Encoding::Identity, payload.bytes()),
+        |(_, encoding, body)| (encoding, body),
+    )
+}
+
+/// Whether the request's `If-None-Match` matches `etag` (via the weak
+/// comparison, as RFC 7232 specifies for `If-None-Match`)
+fn if_none_match(request: &HttpRequest, etag: &str) -> bool {
+    request
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
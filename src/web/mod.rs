@@ -1,4 +1,5 @@
 //! Web authentication, handlers, and middleware
+pub mod admin;
 pub mod dockerflow;
 pub mod extractors;
 pub mod handlers;
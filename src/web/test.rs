@@ -1,25 +1,41 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::sync::{Arc, RwLock};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
 use std::time::Duration;
 
 use actix_cors::Cors;
+use actix_http::http::header::HeaderMap;
 use actix_web::{
-    http::header, http::StatusCode, middleware::errhandlers::ErrorHandlers, test, web, App,
-    HttpRequest, HttpResponse, HttpServer,
+    dev::Service, http::header, http::StatusCode, middleware::errhandlers::ErrorHandlers, test,
+    web, App, HttpRequest, HttpResponse, HttpServer,
 };
+use actix_web_location::Location;
+use async_trait::async_trait;
 use cadence::{SpyMetricSink, StatsdClient};
-use futures::{channel::mpsc, StreamExt};
+use futures::{channel::mpsc, future::join_all, StreamExt};
 use serde_json::{json, Value};
 use url::Url;
 
 use crate::{
-    adm::{AdmFilter, AdmFilterSettings, DEFAULT},
+    adm::{AdmAdvertiserFilterSettings, AdmFilter, AdmFilterSettings, Tile, TileResponse, DEFAULT},
     build_app,
-    error::{HandlerError, HandlerResult},
-    server::{cache, location::location_config_from_settings, ServerState},
-    settings::{test_settings, Settings},
-    web::{dockerflow, handlers, middleware},
+    error::{HandlerError, HandlerErrorKind, HandlerResult},
+    metrics::Metrics,
+    providers::{providers_from_settings, Hedger, RateLimiter, TileProvider},
+    server::{
+        cache,
+        cache_backend::{backend_from_settings, MemoryBackend, TilesCacheBackend},
+        location::location_config_from_settings,
+        refresher,
+        warmup::Warmup,
+        ServerState,
+    },
+    settings::{test_settings, Settings, TestModes},
+    tags::Tags,
+    web::{admin, dockerflow, handlers, middleware, DeviceInfo},
 };
 
 const MOCK_RESPONSE1: &str = include_str!("mock_adm_response1.json");
@@ -52,6 +68,27 @@ macro_rules! init_app_with_spy {
         }
     };
     ($settings:expr) => {
+        async {
+            let providers = providers_from_settings(&$settings).unwrap();
+            init_app_with_spy!($settings, providers).await
+        }
+    };
+    ($settings:expr, $providers:expr) => {
+        async {
+            let tiles_cache =
+                cache::TilesCache::new(10).with_backend(backend_from_settings(&$settings).unwrap());
+            init_app_with_spy!($settings, $providers, tiles_cache).await
+        }
+    };
+    ($settings:expr, $providers:expr, $tiles_cache:expr) => {
+        async {
+            let filter = Arc::new(RwLock::new(
+                HandlerResult::<AdmFilter>::from(&mut $settings).unwrap(),
+            ));
+            init_app_with_spy!($settings, $providers, $tiles_cache, filter).await
+        }
+    };
+    ($settings:expr, $providers:expr, $tiles_cache:expr, $filter:expr) => {
         async {
             crate::logging::init_logging(false).unwrap();
             let (spy, sink) = SpyMetricSink::new();
@@ -67,16 +104,22 @@ macro_rules! init_app_with_spy {
                     .connect_timeout(Duration::from_secs(3))
                     .build()
                     .unwrap(),
-                tiles_cache: cache::TilesCache::new(10),
+                tiles_cache: $tiles_cache,
                 settings: $settings.clone(),
-                filter: Arc::new(RwLock::new(
-                    HandlerResult::<AdmFilter>::from(&mut $settings).unwrap(),
-                )),
+                providers: $providers,
+                filter: $filter,
+                live_updater: None,
+                hedger: Arc::new(Hedger::new(&$settings)),
+                rate_limiter: Arc::new(RateLimiter::new(&$settings)),
+                warmup: Warmup::from_settings(&$settings).unwrap(),
+                key_dimensions: Arc::new(cache::KeyDimensions::from_settings(&$settings).unwrap()),
                 img_store: None,
                 excluded_dmas,
                 start_up: std::time::Instant::now(),
             };
             let location_config = location_config_from_settings(&$settings, &metrics);
+            state.warmup.spawn(&state);
+            refresher::spawn(&state);
 
             let service = test::init_service(build_app!(state, location_config)).await;
             (service, spy)
@@ -91,7 +134,13 @@ macro_rules! init_app {
             let (app, _) = init_app_with_spy!($( $args )*).await;
             app
         }
-    }
+    };
+    ($( $args:expr ),+) => {
+        async {
+            let (app, _) = init_app_with_spy!($( $args ),+).await;
+            app
+        }
+    };
 }
 
 struct MockAdm {
@@ -180,6 +229,29 @@ pub fn adm_settings() -> AdmFilterSettings {
     AdmFilterSettings::try_from(adm_settings.to_string()).unwrap()
 }
 
+/// A request for tiles from Firefox 91 (on Windows)
+fn tiles_request() -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/v1/tiles")
+        .header(header::USER_AGENT, UA_91)
+}
+
+/// The metrics recorded by a `SpyMetricSink` (from its receiver's
+/// `try_iter`)
+fn collect_metrics(spied: impl Iterator<Item = Vec<u8>>) -> Vec<String> {
+    spied.map(|m| String::from_utf8(m).unwrap()).collect()
+}
+
+/// The names of the tiles in a response
+fn names(result: &Value) -> Vec<String> {
+    result["tiles"]
+        .as_array()
+        .expect("!tiles.is_array()")
+        .iter()
+        .map(|tile| tile["name"].as_str().unwrap().to_owned())
+        .collect()
+}
+
 /// Basic integration test
 ///
 /// This is a baseline test ensuring that we can read data returned from the ADM server.
@@ -713,3 +785,1007 @@ async fn test_metrics() {
     assert!(get_metric.contains("ua.os.family:ios"));
     assert!(&metrics[1].contains("endpoint:mobile"));
 }
+
+/// A [TileProvider] serving a fixed set of tiles
+#[derive(Debug)]
+struct StaticProvider {
+    tiles: Vec<Tile>,
+}
+
+#[async_trait(?Send)]
+impl TileProvider for StaticProvider {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    async fn get_tiles(
+        &self,
+        _state: &ServerState,
+        _location: &Location,
+        _device_info: &DeviceInfo,
+        _tags: &mut Tags,
+        _metrics: &Metrics,
+        _headers: Option<&HeaderMap>,
+    ) -> HandlerResult<TileResponse> {
+        Ok(TileResponse {
+            tiles: self.tiles.clone(),
+        })
+    }
+}
+
+/// A [TileProvider] that fails on demand
+#[derive(Debug)]
+struct FlakyProvider {
+    tiles: Vec<Tile>,
+    fail: Arc<AtomicBool>,
+}
+
+#[async_trait(?Send)]
+impl TileProvider for FlakyProvider {
+    fn name(&self) -> &str {
+        "flaky"
+    }
+
+    async fn get_tiles(
+        &self,
+        _state: &ServerState,
+        _location: &Location,
+        _device_info: &DeviceInfo,
+        _tags: &mut Tags,
+        _metrics: &Metrics,
+        _headers: Option<&HeaderMap>,
+    ) -> HandlerResult<TileResponse> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(HandlerErrorKind::AdmServerError().into());
+        }
+        Ok(TileResponse {
+            tiles: self.tiles.clone(),
+        })
+    }
+}
+
+fn house_tile() -> Tile {
+    Tile {
+        id: 1,
+        name: "House".to_owned(),
+        url: "https://www.example.com/".to_owned(),
+        click_url: "https://www.example.com/click".to_owned(),
+        image_url: "https://cdn.example.com/1.jpg".to_owned(),
+        image_size: None,
+        impression_url: "https://www.example.com/impression".to_owned(),
+        position: None,
+    }
+}
+
+#[actix_rt::test]
+async fn custom_provider() {
+    let mut settings = Settings {
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let provider = StaticProvider {
+        tiles: vec![house_tile()],
+    };
+    let providers: Vec<Arc<dyn TileProvider>> = vec![Arc::new(provider)];
+    let mut app = init_app!(settings, providers).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: Value = test::read_body_json(resp).await;
+    let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+    assert_eq!(tiles.len(), 1);
+    assert_eq!(&tiles[0]["name"], "House");
+}
+
+#[actix_rt::test]
+async fn partner_partial_failure() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let partners = json!([
+        {"name": "adm", "endpoint_url": adm.endpoint_url, "priority": 0},
+        // Nothing listens here: this partner always fails
+        {"name": "down", "endpoint_url": "http://127.0.0.1:1/", "priority": 1}
+    ]);
+    let mut settings = Settings {
+        adm_settings: json!(adm_settings()).to_string(),
+        partners: Some(partners.to_string()),
+        ..get_test_settings()
+    };
+    let (mut app, spy) = init_app_with_spy!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let result: Value = test::read_body_json(resp).await;
+    let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+    assert_eq!(tiles.len(), 2);
+
+    let metrics = collect_metrics(spy.try_iter());
+    assert!(metrics
+        .iter()
+        .any(|m| m.starts_with("contile.tiles.partner.success") && m.contains("partner:adm")));
+    assert!(metrics
+        .iter()
+        .any(|m| m.starts_with("contile.tiles.partner.error") && m.contains("partner:down")));
+}
+
+#[actix_rt::test]
+async fn partner_metrics_single() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let (mut app, spy) = init_app_with_spy!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The default, single partner deployment reports partner metrics too
+    let metrics = collect_metrics(spy.try_iter());
+    assert!(metrics
+        .iter()
+        .any(|m| m.starts_with("contile.tiles.partner.success") && m.contains("partner:adm")));
+}
+
+/// A [TileProvider] that tags the request before failing
+#[derive(Debug)]
+struct TaggingProvider {
+    name: &'static str,
+}
+
+#[async_trait(?Send)]
+impl TileProvider for TaggingProvider {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn get_tiles(
+        &self,
+        _state: &ServerState,
+        _location: &Location,
+        _device_info: &DeviceInfo,
+        tags: &mut Tags,
+        _metrics: &Metrics,
+        _headers: Option<&HeaderMap>,
+    ) -> HandlerResult<TileResponse> {
+        tags.add_tag("endpoint", self.name);
+        Err(HandlerErrorKind::PartnerUnavailable(self.name.to_owned()).into())
+    }
+}
+
+#[actix_rt::test]
+async fn partner_tags() {
+    for names in [vec!["first"], vec!["first", "second"]] {
+        let mut settings = Settings {
+            adm_settings: json!(adm_settings()).to_string(),
+            ..get_test_settings()
+        };
+        let providers: Vec<Arc<dyn TileProvider>> = names
+            .into_iter()
+            .map(|name| Arc::new(TaggingProvider { name }) as Arc<dyn TileProvider>)
+            .collect();
+        let (mut app, spy) = init_app_with_spy!(settings, providers).await;
+
+        let req = tiles_request().to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // The providers' tags reach the handler however many there are
+        let metrics = collect_metrics(spy.try_iter());
+        assert!(metrics
+            .iter()
+            .any(|m| m.starts_with("contile.tiles.partner_unavailable")
+                && m.contains("endpoint:first")));
+    }
+}
+
+#[actix_rt::test]
+async fn startup_timeout() {
+    // A partner that never answers in time
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/",
+            web::get().to(|| async {
+                actix_rt::time::delay_for(Duration::from_secs(5)).await;
+                HttpResponse::Ok().finish()
+            }),
+        )
+    })
+    .bind(("127.0.0.1", 0))
+    .expect("Couldn't bind slow partner");
+    let addr = server.addrs().pop().expect("No slow partner addr");
+    server.run();
+
+    let mut settings = Settings {
+        adm_endpoint_url: format!("http://{}:{}/", addr.ip(), addr.port()),
+        adm_settings: json!(adm_settings()).to_string(),
+        adm_timeout: 1,
+        partner_max_retries: 0,
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    // Timeouts while starting up are softened to empty responses
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    // But not afterwards
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_rt::test]
+async fn partner_breaker() {
+    let mut settings = Settings {
+        // Nothing listens here: the partner always fails
+        adm_endpoint_url: "http://127.0.0.1:1/".to_owned(),
+        adm_settings: json!(adm_settings()).to_string(),
+        partner_max_retries: 0,
+        partner_breaker_failures: 1,
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // The breaker's now open: fail fast
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let req = test::TestRequest::get().uri("/__heartbeat__").to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert_eq!(result["partners"]["adm"]["breaker"], "open");
+}
+
+#[actix_rt::test]
+async fn tile_positions() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut adm_settings = adm_settings();
+    adm_settings
+        .advertisers
+        .get_mut("Acme")
+        .expect("No Acme tile")
+        .position = Some(5);
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url,
+        adm_settings: json!(adm_settings).to_string(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Tiles are ordered by position, overriding adM's order: Acme's slot is
+    // past `settings.adm_max_tiles` (currently 2)
+    let result: Value = test::read_body_json(resp).await;
+    let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+    assert_eq!(tiles.len(), 2);
+    assert_eq!(&tiles[0]["name"], "Dunder Mifflin");
+    assert_eq!(&tiles[0]["position"], 1);
+    assert_eq!(&tiles[1]["name"], "Los Pollos Hermanos");
+    assert_eq!(&tiles[1]["position"], 2);
+}
+
+#[actix_rt::test]
+async fn ignore_dmas() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut adm_settings = adm_settings();
+    adm_settings
+        .advertisers
+        .get_mut("Acme")
+        .expect("No Acme tile")
+        .ignore_dmas = Some(vec![819]);
+    adm_settings
+        .advertisers
+        .get_mut(DEFAULT)
+        .expect("No DEFAULT tile")
+        .ignore_dmas = Some(vec![807]);
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url,
+        adm_settings: json!(adm_settings).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        adm_max_tiles: 3,
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    // Acme ignores Seattle
+    let req = tiles_request()
+        .header("X-Test-Location", "US, WA, 819")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert_eq!(
+        names(&result),
+        vec!["Dunder Mifflin", "Los Pollos Hermanos"]
+    );
+
+    // The others fall back to DEFAULT's list, which ignores San Francisco
+    let req = tiles_request()
+        .header("X-Test-Location", "US, CA, 807")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert_eq!(names(&result), vec!["Acme"]);
+}
+
+#[actix_rt::test]
+async fn ignore_dmas_without_dma_key() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut adm_settings = adm_settings();
+    adm_settings
+        .advertisers
+        .get_mut("Acme")
+        .expect("No Acme tile")
+        .ignore_dmas = Some(vec![819]);
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        adm_max_tiles: 3,
+        // DMAs aren't part of the cache key
+        tiles_cache_key_dimensions: Some(json!({"US": ["region"]}).to_string()),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let tiles = |location: &'static str| {
+        tiles_request()
+            .header("X-Test-Location", location)
+            .to_request()
+    };
+    let resp = test::call_service(&mut app, tiles("US, WA, 820")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert!(names(&result).contains(&"Acme".to_owned()));
+
+    // Except for the ignored ones
+    let resp = test::call_service(&mut app, tiles("US, WA, 819")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert_eq!(
+        names(&result),
+        vec!["Dunder Mifflin", "Los Pollos Hermanos"]
+    );
+}
+
+#[actix_rt::test]
+async fn stale_if_error() {
+    let mut settings = Settings {
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let fail = Arc::new(AtomicBool::new(false));
+    let provider = FlakyProvider {
+        tiles: vec![house_tile()],
+        fail: fail.clone(),
+    };
+    let providers: Vec<Arc<dyn TileProvider>> = vec![Arc::new(provider)];
+    let tiles_cache = cache::TilesCache::new(10);
+    let (mut app, spy) = init_app_with_spy!(settings, providers, tiles_cache.clone()).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Let the tiles expire, then fail refreshing them
+    tiles_cache.backdate(Duration::from_secs(settings.tiles_ttl as u64 * 2));
+    fail.store(true, Ordering::SeqCst);
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+    assert_eq!(&tiles[0]["name"], "House");
+
+    let metrics = collect_metrics(spy.try_iter());
+    assert!(metrics
+        .iter()
+        .any(|m| m.starts_with("contile.tiles_cache.stale_if_error")));
+}
+
+#[actix_rt::test]
+async fn query_template() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let query = json!([
+        {"name": "partner", "attribute": "partner_id"},
+        {"name": "cc", "attribute": "country_code"},
+        {"name": "placement", "value": "newtab-v2"}
+    ]);
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        adm_query_template: Some(query.to_string()),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let params = adm.params().await;
+    assert_eq!(params.get("partner"), Some(&"test".to_owned()));
+    assert_eq!(params.get("cc"), Some(&"US".to_owned()));
+    assert_eq!(params.get("placement"), Some(&"newtab-v2".to_owned()));
+    assert_eq!(params.get("country-code"), None);
+    assert_eq!(params.get("sub2"), None);
+}
+
+#[actix_rt::test]
+async fn record_and_replay() {
+    let recording_path =
+        std::env::temp_dir().join(format!("contile-recordings-{}", std::process::id()));
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url,
+        adm_settings: json!(adm_settings()).to_string(),
+        test_mode: TestModes::Record,
+        test_recording_path: recording_path.to_string_lossy().into_owned(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let recorded: Value = test::read_body_json(resp).await;
+
+    let recording =
+        std::fs::read_to_string(recording_path.join("adm/US_none_none_desktop_windows.json"))
+            .expect("No recording");
+    let recording: Value = serde_json::from_str(&recording).unwrap();
+    let url = recording["url"].as_str().unwrap();
+    assert!(url.contains("partner=REDACTED"));
+    assert!(!url.contains("partner=test"));
+    assert_eq!(recording["response"]["tiles"].as_array().unwrap().len(), 3);
+
+    // Replay without a partner
+    let mut settings = Settings {
+        adm_endpoint_url: "http://127.0.0.1:1/".to_owned(),
+        test_mode: TestModes::Replay,
+        ..settings
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let replayed: Value = test::read_body_json(resp).await;
+    assert_eq!(recorded, replayed);
+
+    std::fs::remove_dir_all(&recording_path).unwrap();
+}
+
+#[actix_rt::test]
+async fn audience_fixtures() {
+    let fixtures = std::env::temp_dir().join(format!("contile-fixtures-{}", std::process::id()));
+    let write_fixture = |path: &str, names: &[&str]| {
+        let response: Value = serde_json::from_str(MOCK_RESPONSE1).unwrap();
+        let tiles: Vec<_> = response["tiles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|tile| names.contains(&tile["name"].as_str().unwrap()))
+            .cloned()
+            .collect();
+        let path = fixtures.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, json!({ "tiles": tiles }).to_string()).unwrap();
+    };
+    write_fixture("US/WA/desktop/windows.json", &["Acme"]);
+    write_fixture("US/_/desktop/_.json", &["Dunder Mifflin"]);
+    write_fixture("_/_/_/_.json", &["Los Pollos Hermanos"]);
+
+    let mut settings = Settings {
+        adm_settings: json!(adm_settings()).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        test_mode: TestModes::TestFakeResponse,
+        test_file_path: fixtures.to_string_lossy().into_owned(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    for (location, ua, expected) in &[
+        ("US, WA", UA_91, "Acme"),
+        // Wildcard region and OS
+        ("US, CA", UA_91, "Dunder Mifflin"),
+        // Wildcard everything
+        ("US, WA", UA_IPHONE, "Los Pollos Hermanos"),
+    ] {
+        let req = test::TestRequest::get()
+            .uri("/v1/tiles")
+            .header(header::USER_AGENT, *ua)
+            .header("X-Test-Location", *location)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let result: Value = test::read_body_json(resp).await;
+        let tiles = result["tiles"].as_array().expect("!tiles.is_array()");
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(&tiles[0]["name"], expected);
+    }
+
+    std::fs::remove_dir_all(&fixtures).unwrap();
+}
+
+#[actix_rt::test]
+async fn partner_rate_limit() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url,
+        adm_settings: json!(adm_settings()).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        partner_rate_limit: 0.001,
+        partner_rate_limit_burst: 1,
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    for (location, expected) in &[
+        ("US, WA", StatusCode::OK),
+        // No token left for the partner request
+        ("US, CA", StatusCode::NO_CONTENT),
+        // Cached
+        ("US, WA", StatusCode::OK),
+    ] {
+        let req = tiles_request()
+            .header("X-Test-Location", *location)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), *expected);
+    }
+}
+
+#[actix_rt::test]
+async fn not_modified() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cache_control = resp.headers().get(header::CACHE_CONTROL).unwrap();
+    let max_age: u64 = cache_control
+        .to_str()
+        .unwrap()
+        .strip_prefix("private, max-age=")
+        .expect("Not private")
+        .parse()
+        .unwrap();
+    assert!(max_age <= settings.tiles_ttl as u64 * 3 / 2);
+    let etag = resp.headers().get(header::ETAG).expect("No ETag").clone();
+
+    // Revalidating the cached tiles
+    let req = tiles_request()
+        .header(header::IF_NONE_MATCH, etag.clone())
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+    assert!(test::read_body(resp).await.is_empty());
+
+    let req = tiles_request()
+        .header(header::IF_NONE_MATCH, "\"stale\"")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+}
+
+#[actix_rt::test]
+async fn compressed() {
+    use std::io::Read;
+
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let tiles = |accept_encoding: Option<&str>| {
+        let mut req = tiles_request();
+        if let Some(accept_encoding) = accept_encoding {
+            req = req.header(header::ACCEPT_ENCODING, accept_encoding);
+        }
+        req.to_request()
+    };
+    let resp = test::call_service(&mut app, tiles(None)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept-Encoding");
+    assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+    let json = test::read_body(resp).await;
+
+    let resp = test::call_service(&mut app, tiles(Some("gzip, deflate, br;q=0.5"))).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+        "gzip"
+    );
+    assert_ne!(resp.headers().get(header::ETAG).unwrap(), &etag);
+    let body = test::read_body(resp).await;
+    let mut gunzipped = Vec::new();
+    flate2::read::GzDecoder::new(&body[..])
+        .read_to_end(&mut gunzipped)
+        .unwrap();
+    assert_eq!(gunzipped, json);
+
+    let resp = test::call_service(&mut app, tiles(Some("gzip, br"))).await;
+    assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+    let body = test::read_body(resp).await;
+    let mut unbrotlied = Vec::new();
+    brotli::Decompressor::new(&body[..], 4096)
+        .read_to_end(&mut unbrotlied)
+        .unwrap();
+    assert_eq!(unbrotlied, json);
+
+    let resp = test::call_service(&mut app, tiles(Some("br;q=0, identity"))).await;
+    assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(test::read_body(resp).await, json);
+}
+
+#[actix_rt::test]
+async fn admin() {
+    let adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+    // Disabled by default
+    let req = test::TestRequest::get()
+        .uri("/__admin__/cache")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    settings.admin_token = Some("s3cr3t".to_owned());
+    let backend = Arc::new(MemoryBackend::default());
+    let tiles_cache = cache::TilesCache::new(10)
+        .with_backend(Some(backend.clone() as Arc<dyn TilesCacheBackend>));
+    let providers = providers_from_settings(&settings).unwrap();
+    let mut app = init_app!(settings, providers, tiles_cache).await;
+    let admin_request = |method: &str, uri: &str, token: &str| {
+        let req = match method {
+            "POST" => test::TestRequest::post(),
+            _ => test::TestRequest::get(),
+        };
+        req.uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .to_request()
+    };
+    let resp =
+        test::call_service(&mut app, admin_request("GET", "/__admin__/cache", "guess")).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::get()
+        .uri("/__admin__/cache")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp =
+        test::call_service(&mut app, admin_request("GET", "/__admin__/cache", "s3cr3t")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    let entries = result["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry["state"], "fresh");
+    assert!(entry["size"].as_u64().unwrap() > 0);
+    let audience_key = &entry["audience_key"];
+    assert_eq!(audience_key["form_factor"], "desktop");
+    let shared_key: cache::AudienceKey = serde_json::from_value(audience_key.clone()).unwrap();
+    assert!(backend.get(&shared_key).await.unwrap().is_some());
+
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in audience_key.as_object().unwrap() {
+        match value {
+            Value::Null => {}
+            Value::String(value) => {
+                query.append_pair(name, value);
+            }
+            value => {
+                query.append_pair(name, &value.to_string());
+            }
+        }
+    }
+    let query = query.finish();
+    let resp = test::call_service(
+        &mut app,
+        admin_request(
+            "GET",
+            &format!("/__admin__/cache/entry?{}", query),
+            "s3cr3t",
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert_eq!(result["state"], "fresh");
+    assert_eq!(result["content"]["tiles"][0]["name"], "Acme");
+
+    // Every filter must match
+    for (query, purged) in [
+        ("", None),
+        ("advertiser=Nobody", Some(0)),
+        ("advertiser=acme&os_family=macos", Some(0)),
+        ("advertiser=acme&os_family=windows", Some(1)),
+    ] {
+        let resp = test::call_service(
+            &mut app,
+            admin_request(
+                "POST",
+                &format!("/__admin__/cache/purge?{}", query),
+                "s3cr3t",
+            ),
+        )
+        .await;
+        match purged {
+            Some(purged) => {
+                assert_eq!(resp.status(), StatusCode::OK);
+                let result: Value = test::read_body_json(resp).await;
+                assert_eq!(result["purged"], purged);
+            }
+            None => assert_eq!(resp.status(), StatusCode::BAD_REQUEST),
+        }
+    }
+    let resp =
+        test::call_service(&mut app, admin_request("GET", "/__admin__/cache", "s3cr3t")).await;
+    let result: Value = test::read_body_json(resp).await;
+    assert!(result["entries"].as_array().unwrap().is_empty());
+    assert!(backend.get(&shared_key).await.unwrap().is_none());
+}
+
+#[actix_rt::test]
+async fn warmup() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        tiles_warmup_audiences: Some(
+            json!([{
+                "country_code": "US",
+                "region_code": "WA",
+                "form_factor": "desktop",
+                "os_family": "windows"
+            }])
+            .to_string(),
+        ),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    // The warmup's partner request
+    let params = adm.params().await;
+    assert_eq!(params.get("region-code"), Some(&"WA".to_owned()));
+    let mut warmup = Value::Null;
+    for _ in 0..50 {
+        let req = test::TestRequest::get().uri("/__heartbeat__").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let result: Value = test::read_body_json(resp).await;
+        warmup = result["warmup"].clone();
+        if warmup["state"] == "complete" {
+            break;
+        }
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        warmup,
+        json!({"state": "complete", "total": 1, "completed": 1, "failed": 0, "skipped": 0})
+    );
+
+    // Served from the cache
+    let req = tiles_request()
+        .header("X-Test-Location", "US, WA")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = test::read_body_json(resp).await;
+    assert!(!result["tiles"].as_array().unwrap().is_empty());
+    assert!(adm.request_rx.try_next().is_err());
+}
+
+#[actix_rt::test]
+async fn coalesced_misses() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    // Concurrent misses for the same audience
+    let requests: Vec<_> = (0..5)
+        .map(|_| app.call(tiles_request().to_request()))
+        .collect();
+    for resp in join_all(requests).await {
+        let resp = resp.expect("Request failed");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let result: Value = test::read_body_json(resp).await;
+        assert!(!result["tiles"]
+            .as_array()
+            .expect("!tiles.is_array()")
+            .is_empty());
+    }
+    // Are all served by a single partner request
+    adm.params().await;
+    assert!(adm.request_rx.try_next().is_err());
+}
+
+#[actix_rt::test]
+async fn advertiser_removed() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    let providers = providers_from_settings(&settings).unwrap();
+    let tiles_cache = cache::TilesCache::new(10);
+    let filter = Arc::new(RwLock::new(
+        HandlerResult::<AdmFilter>::from(&mut settings).unwrap(),
+    ));
+    let mut app = init_app!(settings, providers, tiles_cache.clone(), filter.clone()).await;
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(names(&test::read_body_json(resp).await).contains(&"Dunder Mifflin".to_owned()));
+    adm.params().await;
+
+    // As the filter updaters do
+    let mut update = AdmFilterSettings::default();
+    update.advertisers.insert(
+        "Dunder Mifflin".to_owned(),
+        AdmAdvertiserFilterSettings {
+            delete: true,
+            ..Default::default()
+        },
+    );
+    let changed = filter.write().unwrap().apply(update);
+    assert_eq!(tiles_cache.invalidate_advertisers(&changed).len(), 1);
+
+    let req = tiles_request().to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let served = names(&test::read_body_json(resp).await);
+    assert!(!served.is_empty());
+    assert!(!served.contains(&"Dunder Mifflin".to_owned()));
+    // Refetched
+    adm.params().await;
+}
+
+#[actix_rt::test]
+async fn key_dimensions() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        tiles_cache_key_dimensions: Some(json!({"US": ["dma"], "CA": ["region"]}).to_string()),
+        ..get_test_settings()
+    };
+    let mut app = init_app!(settings).await;
+
+    let tiles = |location: &'static str| {
+        tiles_request()
+            .header("X-Test-Location", location)
+            .to_request()
+    };
+    let resp = test::call_service(&mut app, tiles("US, WA")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    // The region's left out of the request
+    let params = adm.params().await;
+    assert_eq!(params.get("country-code"), Some(&"US".to_owned()));
+    assert_eq!(params.get("region-code"), Some(&"".to_owned()));
+
+    // And the cache key
+    let resp = test::call_service(&mut app, tiles("US, OR")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(adm.request_rx.try_next().is_err());
+}
+
+#[actix_rt::test]
+async fn refresh_ahead() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        location_test_header: Some("x-test-location".to_owned()),
+        // Everything's about to expire
+        tiles_refresh_ahead_secs: 60 * 60,
+        tiles_refresh_interval_ms: 50,
+        ..get_test_settings()
+    };
+    // Which also holds for the shared tiles: they're not reused
+    let tiles_cache = cache::TilesCache::new(10).with_backend(Some(
+        Arc::new(MemoryBackend::default()) as Arc<dyn TilesCacheBackend>,
+    ));
+    let providers = providers_from_settings(&settings).unwrap();
+    let (mut app, spy) = init_app_with_spy!(settings, providers, tiles_cache).await;
+
+    let req = tiles_request()
+        .header("X-Test-Location", "US, WA")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let params = adm.params().await;
+    assert_eq!(params.get("region-code"), Some(&"WA".to_owned()));
+
+    // Refreshed in the background
+    let params = adm.params().await;
+    assert_eq!(params.get("region-code"), Some(&"WA".to_owned()));
+    let mut completed = false;
+    for _ in 0..50 {
+        completed = collect_metrics(spy.try_iter())
+            .iter()
+            .any(|m| m.starts_with("contile.tiles_cache.refresh.completed:1|"));
+        if completed {
+            break;
+        }
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert!(completed);
+}
+
+#[actix_rt::test]
+async fn shared_cache() {
+    let mut adm = init_mock_adm(MOCK_RESPONSE1.to_owned());
+    let mut settings = Settings {
+        adm_endpoint_url: adm.endpoint_url.clone(),
+        adm_settings: json!(adm_settings()).to_string(),
+        ..get_test_settings()
+    };
+    // Two instances sharing the backend
+    let backend: Arc<dyn TilesCacheBackend> = Arc::new(MemoryBackend::default());
+    let mut apps = Vec::new();
+    for _ in 0..2 {
+        let tiles_cache = cache::TilesCache::new(10).with_backend(Some(backend.clone()));
+        let providers = providers_from_settings(&settings).unwrap();
+        apps.push(init_app!(settings, providers, tiles_cache).await);
+    }
+
+    let mut tiles = Vec::new();
+    for app in apps.iter_mut() {
+        let req = tiles_request().to_request();
+        let resp = test::call_service(app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let result: Value = test::read_body_json(resp).await;
+        tiles.push(result);
+    }
+    assert_eq!(tiles[0], tiles[1]);
+    // Only the first instance requested tiles from the partner
+    adm.params().await;
+    assert!(adm.request_rx.try_next().is_err());
+}
@@ -3,12 +3,14 @@
 use std::fmt;
 use std::str::FromStr;
 
+use serde::{Deserialize, Serialize};
 use woothee::parser::Parser;
 
 use crate::error::{HandlerError, HandlerErrorKind, HandlerResult};
 
 /// ADM required browser format form
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FormFactor {
     Desktop,
     Phone,
@@ -24,7 +26,8 @@ impl fmt::Display for FormFactor {
 }
 
 /// Simplified Operating System Family
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OsFamily {
     Windows,
     MacOs,